@@ -0,0 +1,35 @@
+use std::process::{Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Run git with `args`, and return its output if it succeeds.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git").args(args).output().ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_owned())
+}
+
+/// Record build information for the `/version` route.
+fn main() {
+    let commit = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=OCULARITY_GIT_COMMIT={}", commit);
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    println!("cargo:rustc-env=OCULARITY_BUILD_TIME={}", time);
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=OCULARITY_FEATURES={}", features.join(","));
+    // Run again when the commit changes, i.e. when HEAD moves to another
+    // branch or the branch moves, as well as when the sources change.
+    let mut watched = vec!["HEAD".to_owned(), "packed-refs".to_owned()];
+    watched.extend(git(&["symbolic-ref", "-q", "HEAD"]));
+    for path in watched {
+        if let Some(path) = git(&["rev-parse", "--git-path", &path]) {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    for path in ["build.rs", "Cargo.toml", "src"] {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+}
@@ -0,0 +1,352 @@
+//! The server configuration, and where it comes from.
+
+use std::error::{Error};
+use std::path::{Path, PathBuf};
+
+use log::{LevelFilter};
+use url::{Url};
+
+use crate::util::{json_string};
+
+const BASE_URL: &str = "https://www.minworks.co.uk";
+
+/// An invalid configuration setting, with a description of where it came
+/// from.
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// The server configuration.
+///
+/// Each setting can be given in a TOML file as `key = value`, overridden by
+/// an environment variable `OCULARITY_KEY`, overridden by a command-line
+/// flag `--key value` (with `_` written as `-`).
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The address to listen on.
+    pub address: String,
+    /// A separate address on which to serve the admin pages, instead of
+    /// `address`, if any. It should be reachable only from inside.
+    pub admin_address: Option<String>,
+    /// The URL at which the server is publicly visible.
+    pub base_url: Url,
+    /// The token required by the admin pages, if they are enabled.
+    pub admin_token: Option<String>,
+    /// The most verbose level of log message to write.
+    pub log_level: LevelFilter,
+    /// The file to write log messages to, or `None` for standard error.
+    pub log_file: Option<PathBuf>,
+    /// The file to write one line per request to, or `None` for the log file.
+    pub access_log: Option<PathBuf>,
+    /// The size at which log files are rotated, or `0` for never.
+    pub log_max_bytes: u64,
+    /// The file to write the server's process ID to, if any.
+    pub pid_file: Option<PathBuf>,
+    /// The `Retry-After` time in seconds sent in maintenance mode.
+    pub maintenance_retry_after: u64,
+    /// The time in seconds between a shutdown request and when the server
+    /// stops accepting requests.
+    pub drain_grace: u64,
+    /// The time in seconds allowed for serving requests that arrived before
+    /// the server stopped accepting.
+    pub shutdown_timeout: u64,
+    /// If `true`, take the client address from `X-Forwarded-For`, which
+    /// must then be set by a reverse proxy. It is never trusted on the admin
+    /// listener.
+    pub trust_forwarded_for: bool,
+    /// Problems with the settings that were ignored rather than refused,
+    /// such as a `RUST_LOG` that sets no level. They are found before
+    /// logging is set up, so the caller must report them.
+    pub warnings: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "127.0.0.1:8081".to_owned(),
+            admin_address: None,
+            base_url: Url::parse(BASE_URL).unwrap(), // depends only on data fixed at compile time
+            admin_token: None,
+            log_level: LevelFilter::Info,
+            log_file: None,
+            access_log: None,
+            log_max_bytes: 10 << 20,
+            pid_file: None,
+            maintenance_retry_after: 3600,
+            drain_grace: 10,
+            shutdown_timeout: 10,
+            trust_forwarded_for: false,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The names and descriptions of all settings.
+    pub const SETTINGS: &'static [(&'static str, &'static str)] = &[
+        ("address", "The host:port to listen on"),
+        ("admin_address", "A separate, internal host:port for the admin pages"),
+        ("base_url", "The URL at which the server is publicly visible"),
+        ("admin_token", "The token required by the admin pages"),
+        ("log_level", "One of off, error, warn, info, debug, trace"),
+        ("log_file", "The log file (default: standard error)"),
+        ("access_log", "The access log file (default: the log file)"),
+        ("log_max_bytes", "The size at which log files are rotated"),
+        ("pid_file", "A file to write the server's process ID to"),
+        ("maintenance_retry_after", "Seconds after which to retry in maintenance mode"),
+        ("drain_grace", "Seconds to keep serving after a shutdown request"),
+        ("shutdown_timeout", "Seconds allowed to finish queued requests at shutdown"),
+        ("trust_forwarded_for", "Whether to trust X-Forwarded-For from a reverse proxy"),
+    ];
+
+    /// Write the settings in TOML, omitting secrets, in the order of
+    /// [`Self::SETTINGS`].
+    pub fn to_toml(&self) -> String {
+        let path = |path: &Option<PathBuf>| path.as_ref().map(|p| p.display().to_string());
+        let values = [
+            ("address", Some(json_string(&self.address))),
+            ("admin_address", self.admin_address.as_deref().map(json_string)),
+            ("base_url", Some(json_string(self.base_url.as_str()))),
+            ("log_level", Some(json_string(&self.log_level.to_string().to_lowercase()))),
+            ("log_file", path(&self.log_file).map(|p| json_string(&p))),
+            ("access_log", path(&self.access_log).map(|p| json_string(&p))),
+            ("log_max_bytes", Some(self.log_max_bytes.to_string())),
+            ("pid_file", path(&self.pid_file).map(|p| json_string(&p))),
+            ("maintenance_retry_after", Some(self.maintenance_retry_after.to_string())),
+            ("drain_grace", Some(self.drain_grace.to_string())),
+            ("shutdown_timeout", Some(self.shutdown_timeout.to_string())),
+            ("trust_forwarded_for", Some(self.trust_forwarded_for.to_string())),
+        ];
+        let mut ret = String::new();
+        for (key, value) in values {
+            if let Some(value) = value { ret.push_str(&format!("{} = {}\n", key, value)); }
+        }
+        ret
+    }
+
+    /// Set the setting called `key` to `value`, or explain what is wrong.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "address" | "admin_address" => {
+                let port = value.rsplit_once(':').map(|(_, port)| port);
+                if port.and_then(|port| port.parse::<u16>().ok()).is_none() {
+                    return Err(format!("expected host:port, not `{}`", value));
+                }
+                if key == "address" { self.address = value.to_owned(); } else { self.admin_address = Some(value.to_owned()); }
+            },
+            "base_url" => {
+                let url = Url::parse(value).map_err(|e| format!("`{}`: {}", value, e))?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    return Err(format!("`{}` is not an http or https URL", value));
+                }
+                self.base_url = url;
+            },
+            "admin_token" => {
+                if value.is_empty() { return Err("must not be empty".to_owned()); }
+                self.admin_token = Some(value.to_owned());
+            },
+            "log_level" => {
+                self.log_level = value.parse().map_err(|_| format!("unknown log level `{}`", value))?;
+            },
+            "log_file" => { self.log_file = Some(value.into()); },
+            "access_log" => { self.access_log = Some(value.into()); },
+            "pid_file" => { self.pid_file = Some(value.into()); },
+            "maintenance_retry_after" | "drain_grace" | "shutdown_timeout" => {
+                let seconds = value.parse().map_err(|_| format!("expected a number of seconds, not `{}`", value))?;
+                match key {
+                    "maintenance_retry_after" => { self.maintenance_retry_after = seconds; },
+                    "drain_grace" => { self.drain_grace = seconds; },
+                    _ => { self.shutdown_timeout = seconds; },
+                }
+            },
+            "trust_forwarded_for" => {
+                self.trust_forwarded_for = value.parse().map_err(|_| format!("expected true or false, not `{}`", value))?;
+            },
+            "log_max_bytes" => {
+                self.log_max_bytes = value.parse().map_err(|_| format!("expected a number of bytes, not `{}`", value))?;
+            },
+            _ => return Err(format!("unknown setting `{}`", key)),
+        }
+        Ok(())
+    }
+
+    /// Apply the settings in the TOML file at `path`.
+    ///
+    /// Only a flat subset of TOML is supported: `key = value` lines, where
+    /// `value` is a basic string, integer or boolean, and `#` comments.
+    pub fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(
+            |e| ConfigError(format!("{}: {}", path.display(), e))
+        )?;
+        for (index, line) in text.lines().enumerate() {
+            let error = |e: String| ConfigError(format!("{}:{}: {}", path.display(), index + 1, e));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let (key, value) = line.split_once('=').ok_or_else(|| error("expected `key = value`".to_owned()))?;
+            let value = parse_toml_value(value.trim()).map_err(error)?;
+            self.set(key.trim(), &value).map_err(|e| error(format!("{}: {}", key.trim(), e)))?;
+        }
+        Ok(())
+    }
+
+    /// Apply the settings in `OCULARITY_*` environment variables, and the
+    /// conventional `RUST_LOG`, which `OCULARITY_LOG_LEVEL` overrides.
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Ok(value) = std::env::var("RUST_LOG") {
+            match rust_log_level(&value) {
+                Some(level) => { self.log_level = level; },
+                None => {
+                    self.warnings.push(format!("Ignoring RUST_LOG `{}`: it sets no level for ocularity", value));
+                },
+            }
+        }
+        for (key, _) in Self::SETTINGS {
+            let name = format!("OCULARITY_{}", key.to_uppercase());
+            if let Ok(value) = std::env::var(&name) {
+                self.set(key, &value).map_err(|e| ConfigError(format!("{}: {}", name, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a setting given as the command-line flag `--flag value`.
+    pub fn apply_flag(&mut self, flag: &str, value: &str) -> Result<(), ConfigError> {
+        let key = flag.strip_prefix("--").unwrap_or(flag).replace('-', "_");
+        self.set(&key, value).map_err(|e| ConfigError(format!("{}: {}", flag, e)))
+    }
+}
+
+/// Where to read the configuration from, so that it can be reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    /// The `--config` file, if any.
+    pub path: Option<PathBuf>,
+    /// The `--key value` flags, in order.
+    pub flags: Vec<(String, String)>,
+    /// If `true`, `OCULARITY_*` and `RUST_LOG` are not read.
+    pub ignore_env: bool,
+}
+
+impl ConfigSource {
+    /// Read the configuration from the file, the environment (unless
+    /// `ignore_env`) and the flags, in increasing order of precedence.
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        if let Some(path) = &self.path { config.apply_file(path)?; }
+        if !self.ignore_env { config.apply_env()?; }
+        for (flag, value) in &self.flags { config.apply_flag(flag, value)?; }
+        Ok(config)
+    }
+}
+
+/// The level that `RUST_LOG` directives such as `warn,ocularity=debug` set
+/// for this program, if any.
+///
+/// A directive for the `ocularity` target takes precedence over a bare
+/// level. Directives for other targets, `/filter` suffixes and unparseable
+/// directives are ignored, since [`crate::logging::Logger`] filters only by
+/// level.
+fn rust_log_level(value: &str) -> Option<LevelFilter> {
+    let mut global = None;
+    let mut ours = None;
+    for directive in value.split('/').next().unwrap().split(',') {
+        match directive.trim().split_once('=') {
+            None => { global = directive.trim().parse().ok().or(global); },
+            Some((target, level)) if target == "ocularity" || target.starts_with("ocularity::") => {
+                ours = level.parse().ok().or(ours);
+            },
+            Some(_) => {},
+        }
+    }
+    ours.or(global)
+}
+
+/// Parse a TOML basic string, integer or boolean, returning it as text.
+fn parse_toml_value(value: &str) -> Result<String, String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut ret = String::new();
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    let rest = chars.as_str().trim();
+                    if !(rest.is_empty() || rest.starts_with('#')) {
+                        return Err(format!("unexpected `{}` after string", rest));
+                    }
+                    return Ok(ret);
+                },
+                '\\' => match chars.next() {
+                    Some('"') => ret.push('"'),
+                    Some('\\') => ret.push('\\'),
+                    Some('n') => ret.push('\n'),
+                    Some('t') => ret.push('\t'),
+                    _ => return Err("unsupported escape sequence".to_owned()),
+                },
+                _ => ret.push(c),
+            }
+        }
+        return Err("unterminated string".to_owned());
+    }
+    let value = value.split('#').next().unwrap().trim();
+    if value == "true" || value == "false" || value.parse::<i64>().is_ok() {
+        Ok(value.to_owned())
+    } else {
+        Err(format!("expected a string, integer or boolean, not `{}`", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_values() {
+        assert_eq!(parse_toml_value(r#""plain""#), Ok("plain".to_owned()));
+        assert_eq!(parse_toml_value(r#""a \"b\" \\ c\n\t""#), Ok("a \"b\" \\ c\n\t".to_owned()));
+        assert_eq!(parse_toml_value(r#""has # hash" # comment"#), Ok("has # hash".to_owned()));
+        assert_eq!(parse_toml_value("42 # comment"), Ok("42".to_owned()));
+        assert_eq!(parse_toml_value("-1"), Ok("-1".to_owned()));
+        assert_eq!(parse_toml_value("true"), Ok("true".to_owned()));
+        assert!(parse_toml_value(r#""unterminated"#).is_err());
+        assert!(parse_toml_value(r#""bad \x escape""#).is_err());
+        assert!(parse_toml_value(r#""a" "b""#).is_err());
+        assert!(parse_toml_value("bare").is_err());
+        assert!(parse_toml_value("1.5").is_err());
+    }
+
+    #[test]
+    fn rust_log() {
+        assert_eq!(rust_log_level("debug"), Some(LevelFilter::Debug));
+        assert_eq!(rust_log_level("info,ocularity=debug"), Some(LevelFilter::Debug));
+        assert_eq!(rust_log_level("ocularity::server=trace,warn"), Some(LevelFilter::Trace));
+        assert_eq!(rust_log_level("warn,tiny_http=error"), Some(LevelFilter::Warn));
+        assert_eq!(rust_log_level("error/foo.*bar"), Some(LevelFilter::Error));
+        assert_eq!(rust_log_level(" off , "), Some(LevelFilter::Off));
+        assert_eq!(rust_log_level("tiny_http=debug"), None);
+        assert_eq!(rust_log_level("ocularity"), None);
+        assert_eq!(rust_log_level("loud"), None);
+        assert_eq!(rust_log_level(""), None);
+    }
+
+    #[test]
+    fn settings() {
+        let mut config = Config::default();
+        assert!(config.set("address", "0.0.0.0:80").is_ok());
+        assert!(config.set("address", "0.0.0.0").is_err());
+        assert!(config.set("address", "0.0.0.0:99999").is_err());
+        assert!(config.set("base_url", "ftp://example.com/").is_err());
+        assert!(config.set("admin_token", "").is_err());
+        assert!(config.set("admin_token", "s3cret").is_ok());
+        assert!(config.set("log_level", "loud").is_err());
+        assert!(config.set("drain_grace", "-1").is_err());
+        assert!(config.set("no_such_setting", "1").is_err());
+        assert!(!config.to_toml().contains("s3cret"));
+    }
+}
@@ -0,0 +1,13 @@
+//! Ocularity: a colour perception experiment served over HTTP.
+
+pub mod config;
+pub mod logging;
+pub mod router;
+pub mod server;
+pub mod signals;
+pub mod stats;
+pub mod stimulus;
+pub mod util;
+
+#[cfg(test)]
+mod testing;
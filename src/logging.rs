@@ -0,0 +1,133 @@
+//! Log files and the logger that writes them.
+
+use std::cell::{RefCell};
+use std::collections::hash_map::{RandomState};
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config};
+use crate::util::{utc_timestamp};
+
+/// The number of old log files kept by rotation.
+const LOG_FILES_KEPT: usize = 5;
+
+/// A log file, which is renamed to `<path>.1` (and so on) when it grows past
+/// a size limit.
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_bytes: u64) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(
+            |e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+        )?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {path: path.to_owned(), file, size, max_bytes})
+    }
+
+    /// The name of the `index`th old log file.
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.max_bytes > 0 && self.size > 0 && self.size + line.len() as u64 >= self.max_bytes {
+            for index in (1..LOG_FILES_KEPT).rev() {
+                let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+            *self = LogFile::open(&self.path, self.max_bytes)?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// The ID of the request being handled by this thread, if any.
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Record that this thread is handling the request with ID `id`, or none.
+pub fn set_request_id(id: Option<&str>) {
+    REQUEST_ID.with(|cell| *cell.borrow_mut() = id.map(str::to_owned));
+}
+
+/// Generate a short random ID for a request.
+pub fn new_request_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    format!("{:08x}", hasher.finish() as u32)
+}
+
+/// The log target of the per-request access log lines.
+pub const ACCESS: &str = "access";
+
+/// Writes log messages to standard error or a [`LogFile`], and the access
+/// log to a separate file if configured.
+///
+/// Messages are filtered only by [`log::max_level()`], so that the level can
+/// be changed while running.
+#[derive(Debug)]
+pub struct Logger {
+    log_file: Option<Mutex<LogFile>>,
+    access_log: Option<Mutex<LogFile>>,
+}
+
+impl Logger {
+    /// Construct a `Logger` as described by `config`, and install it.
+    pub fn init(config: &Config) -> std::io::Result<()> {
+        let open = |path: &Option<PathBuf>| -> std::io::Result<_> {
+            Ok(match path {
+                Some(path) => Some(Mutex::new(LogFile::open(path, config.log_max_bytes)?)),
+                None => None,
+            })
+        };
+        let logger = Logger {log_file: open(&config.log_file)?, access_log: open(&config.access_log)?};
+        log::set_boxed_logger(Box::new(logger)).expect("Logger already installed");
+        log::set_max_level(config.log_level);
+        Ok(())
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) { return; }
+        let time = utc_timestamp(SystemTime::now());
+        let id = REQUEST_ID.with(|id| id.borrow().clone()).unwrap_or_else(|| "-".to_owned());
+        let (file, line) = if record.target() == ACCESS && self.access_log.is_some() {
+            (&self.access_log, format!("{} {} {}", time, id, record.args()))
+        } else {
+            (&self.log_file, format!("{} {:<5} {} {}", time, record.level(), id, record.args()))
+        };
+        match file {
+            Some(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                file.write_line(&line).unwrap_or_else(|e| eprintln!("Cannot write log: {}", e));
+            },
+            None => { eprintln!("{}", line); },
+        }
+    }
+
+    fn flush(&self) {
+        for file in [&self.log_file, &self.access_log].into_iter().flatten() {
+            let _ = file.lock().unwrap_or_else(|e| e.into_inner()).file.flush();
+        }
+    }
+}
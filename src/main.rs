@@ -1,138 +1,297 @@
-use std::collections::{HashMap};
 use std::error::{Error};
-use std::fs::{File};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader};
 use std::path::{Path};
-use std::str::{Split};
+use std::panic::{self};
+use std::process::{Stdio};
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use tiny_http::{Method, Request, Response, Header};
-use url::{Url};
+use ocularity::config::{Config, ConfigError, ConfigSource};
+use ocularity::logging::{Logger};
+use ocularity::server::{HttpOkay, Listener, Ocularity, Params, STATIC_FILES};
+use ocularity::signals::{self};
+use ocularity::stimulus::{image};
+use ocularity::util::{fnv1a};
 
-// ----------------------------------------------------------------------------
-
-/// A "200 OK" HTTP response.
-#[derive(Debug)]
-pub enum HttpOkay {
-    File(File),
-    Text(String),
-    Data(Vec<u8>),
-}
-
-// An erroneous HTTP response.
-#[derive(Debug)]
-pub enum HttpError {
-    Invalid,
-    NotFound,
-    Error(Box<dyn Error>),
+/// A subcommand of the command-line interface.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Run the server, optionally in the background.
+    Serve {daemon: bool},
+    /// Validate the configuration and exit.
+    CheckConfig,
 }
 
-impl std::fmt::Display for HttpError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
+/// The command-line usage message.
+fn usage() -> String {
+    let mut ret = String::from(
+"Usage: ocularity [COMMAND] [OPTIONS]
 
-impl Error for HttpError {}
+Commands:
+  serve                  Run the server (the default)
+  check-config           Validate the configuration and exit
 
-macro_rules! impl_from_for_error {
-    ($e:ty) => {
-        impl From<$e> for HttpError {
-            fn from(e: $e) -> Self { HttpError::Error(e.into()) }
-        }
-    };
-}
-
-impl_from_for_error!(std::io::Error);
-impl_from_for_error!(std::num::ParseIntError);
-impl_from_for_error!(url::ParseError);
-impl_from_for_error!(png::EncodingError);
+Options:
+  --config <FILE>        Read settings from a TOML file
+  --daemon               Run the server in the background (needs --log-file)
+");
+    for (key, help) in Config::SETTINGS {
+        let flag = format!("--{} <VALUE>", key.replace('_', "-"));
+        ret.push_str(&format!("  {:<22} {}\n", flag, help));
+    }
+    ret.push_str(
+"  -h, --help             Print this message and exit
+  -V, --version          Print the version and exit
 
-fn header(key: &str, value: &str) -> tiny_http::Header {
-    let key_b = key.as_bytes();
-    let val_b = value.as_bytes();
-    Header::from_bytes(
-        key_b, val_b)
-        .unwrap() // depends only on data fixed at compile time
+Each setting can also be given as an environment variable OCULARITY_<KEY>.
+Flags override environment variables, which override the config file.
+");
+    ret
 }
 
-// ----------------------------------------------------------------------------
-
-fn main() -> Result<(), Box<dyn Error>> {
-    let server = tiny_http::Server::http("127.0.0.1:8081").unwrap();
-    for request in server.incoming_requests() {
-        match handle_request(&request) {
-            Ok(HttpOkay::File(file)) => {
-                request.respond(Response::from_file(file))
-            },
-            Ok(HttpOkay::Text(text)) => {
-                request.respond(Response::from_string(text))
-            },
-            Ok(HttpOkay::Data(data)) => {
-                let header = header("Content-Type", "image/png");
-                request.respond(Response::from_data(data).with_header(header))
+/// Parse the command line `args` (excluding the program name).
+fn parse_args(args: impl IntoIterator<Item=String>) -> Result<(Command, ConfigSource), ConfigError> {
+    let mut command = None;
+    let mut daemon = false;
+    let mut source = ConfigSource::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", usage());
+                std::process::exit(0);
             },
-            Err(HttpError::Invalid) => {
-                request.respond(Response::from_string("Invalid request").with_status_code(400))
+            "-V" | "--version" => {
+                println!("ocularity {}", env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
             },
-            Err(HttpError::NotFound) => {
-                request.respond(Response::from_string("Not found").with_status_code(404))
+            "serve" if command.is_none() => { command = Some(Command::Serve {daemon: false}); },
+            "--daemon" => { daemon = true; },
+            "check-config" if command.is_none() => { command = Some(Command::CheckConfig); },
+            _ if arg.starts_with("--") => {
+                let (flag, value) = match arg.split_once('=') {
+                    Some((flag, value)) => (flag.to_owned(), value.to_owned()),
+                    None => {
+                        let value = args.next().ok_or_else(|| ConfigError(format!("{}: missing value", arg)))?;
+                        (arg, value)
+                    },
+                };
+                if flag == "--config" { source.path = Some(value.into()); } else { source.flags.push((flag, value)); }
             },
-            Err(e) => {
-                println!("Error: {}", e);
-                request.respond(Response::from_string("Internal error").with_status_code(500))
-            },
-        }.unwrap_or_else(|e2| println!("IO Error: {}", e2));
+            _ => return Err(ConfigError(format!("unexpected argument `{}` (try --help)", arg))),
+        }
     }
-    Ok(())
+    let command = match command.unwrap_or(Command::Serve {daemon: false}) {
+        Command::Serve {..} => Command::Serve {daemon},
+        _ if daemon => return Err(ConfigError("--daemon: only applies to `serve`".to_owned())),
+        command => command,
+    };
+    Ok((command, source))
 }
 
-const BASE_URL: &'static str = "https://www.minworks.co.uk";
+/// The environment variable that asks the server to print [`READY`] on
+/// standard output once it is listening.
+const NOTIFY_READY: &str = "OCULARITY_NOTIFY_READY";
 
-fn handle_request(request: &Request) -> Result<HttpOkay, HttpError> {
-    match request.method() {
-        Method::Get => {},
-        _ => return Err(HttpError::Invalid),
-    }
+/// What the server prints when it is listening, if asked to.
+const READY: &str = "ready";
 
-    let url = request.url();
-    let url = url_escape::decode(url).into_owned();
-    let url = Url::parse(BASE_URL).unwrap().join(&url)?;
-    println!("{:?}", url);
-    let params: HashMap<String, String> = url.query_pairs().map(
-        |(key, value)| (key.into_owned(), value.into_owned())
-    ).collect();
-    println!("{:?}", params);
-    let mut path = url.path_segments().unwrap();
-    match path.next() {
-        Some("hello") => Ok(HttpOkay::Text("Hello, Martin!".to_owned())),
-        Some("static") => static_file(path, params),
-        Some("image.png") => image(path, params),
-        _ => Err(HttpError::NotFound),
-    }
-    
+/// Run this program again in the background with the same arguments but
+/// without `--daemon`, wait until it is listening, and return its process ID.
+///
+/// The new process runs in a new session, detached from the terminal. Its
+/// standard error is appended to `log_file`, so that errors during startup
+/// are not lost.
+fn daemonize(log_file: &Path) -> std::io::Result<u32> {
+    use std::os::unix::process::{CommandExt};
+    extern "C" {
+        fn setsid() -> i32;
+    }
+    let args = std::env::args().skip(1).filter(|arg| arg != "--daemon");
+    let stderr = OpenOptions::new().create(true).append(true).open(log_file)?;
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command.args(args)
+        .env(NOTIFY_READY, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(stderr);
+    // SAFETY: `setsid()` is async-signal-safe.
+    unsafe {
+        command.pre_exec(|| {
+            if setsid() < 0 { return Err(std::io::Error::last_os_error()); }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line)?;
+    if line.trim() != READY {
+        let status = child.wait()?;
+        return Err(std::io::Error::other(format!(
+            "the server stopped during startup ({}); see {}", status, log_file.display(),
+        )));
+    }
+    Ok(child.id())
 }
 
-// ----------------------------------------------------------------------------
-
-fn static_file(mut path: Split<char>, _params: HashMap<String, String>) -> Result<HttpOkay, HttpError> {
-    if let Some(name) = path.next() {
-        if name != ".." {
-            return Ok(HttpOkay::File(File::open(&Path::new(name))?));
+/// Check that the server can work with `config`, and describe any problems
+/// in a way that suggests how to fix them.
+fn self_check(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    if config.admin_address.as_ref() == Some(&config.address) {
+        problems.push("admin_address must be different from address".to_owned());
+    }
+    if config.log_file.is_some() && config.log_file == config.access_log {
+        problems.push("log_file and access_log must be different files".to_owned());
+    }
+    if config.pid_file.is_some() && [&config.log_file, &config.access_log].contains(&&config.pid_file) {
+        problems.push("pid_file must not be a log file".to_owned());
+    }
+    if config.base_url.cannot_be_a_base() || config.base_url.query().is_some() || config.base_url.fragment().is_some() {
+        problems.push(format!("base_url `{}` must be a plain http or https URL", config.base_url));
+    }
+    if let Some(token) = &config.admin_token {
+        // Logging is not set up yet.
+        if token.len() < 16 { eprintln!("Warning: admin_token is short; consider at least 16 random characters"); }
+    }
+    for name in STATIC_FILES {
+        if let Err(e) = File::open(name) {
+            problems.push(format!(
+                "cannot read static file `{}` ({}); run ocularity from the directory containing it",
+                name, e,
+            ));
         }
     }
-    Err(HttpError::Invalid)
+    // Render a test image and check that it decodes to the requested colour.
+    let params: Params = [("r", "1"), ("g", "2"), ("b", "3")].iter()
+        .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+    let decoded = match image(&params) {
+        Ok(HttpOkay::Data(data)) => {
+            png::Decoder::new(&data[..]).read_info().ok().and_then(|mut reader| {
+                let mut pixel = vec![0; reader.output_buffer_size()];
+                reader.next_frame(&mut pixel).ok().map(|_| pixel)
+            })
+        },
+        _ => None,
+    };
+    if decoded.as_deref() != Some(&[1, 2, 3]) {
+        problems.push("the PNG encoder produced a wrong test image".to_owned());
+    }
+    problems
 }
 
-// ----------------------------------------------------------------------------
-
-fn image(_path: Split<char>, params: HashMap<String, String>) -> Result<HttpOkay, HttpError> {
-    let r = params.get("r").ok_or(HttpError::Invalid)?.parse::<u8>()?;
-    let g = params.get("g").ok_or(HttpError::Invalid)?.parse::<u8>()?;
-    let b = params.get("b").ok_or(HttpError::Invalid)?.parse::<u8>()?;
-    let mut buf: Vec<u8> = Vec::new();
-    let mut encoder = png::Encoder::new(&mut buf, 1, 1);
-    encoder.set_color(png::ColorType::Rgb);
-    let mut writer = encoder.write_header().unwrap();
-    writer.write_image_data(&[r, g, b])?;
-    writer.finish()?;
-    Ok(HttpOkay::Data(buf))
+fn main() -> Result<(), Box<dyn Error>> {
+    let (command, source) = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("Configuration error: {}", e);
+        std::process::exit(2);
+    });
+    let mut ocularity = Ocularity::builder().source(source).build().unwrap_or_else(|e| {
+        eprintln!("Configuration error: {}", e);
+        std::process::exit(2);
+    });
+    let config = ocularity.config().clone();
+    for warning in &config.warnings { eprintln!("Warning: {}", warning); }
+    let problems = self_check(&config);
+    if !problems.is_empty() {
+        for problem in problems { eprintln!("Configuration error: {}", problem); }
+        std::process::exit(2);
+    }
+    if let Command::Serve {daemon: true} = command {
+        let Some(log_file) = &config.log_file else {
+            eprintln!("Configuration error: --daemon: log_file must be set");
+            std::process::exit(2);
+        };
+        let pid = daemonize(log_file).unwrap_or_else(|e| {
+            eprintln!("Cannot start in the background: {}", e);
+            std::process::exit(1);
+        });
+        println!("Started ocularity in the background as process {}", pid);
+        return Ok(());
+    }
+    if command == Command::CheckConfig {
+        print!("{}", config.to_toml());
+        println!("# admin pages: {}", if config.admin_token.is_some() { "enabled" } else { "disabled" });
+        println!("# config_hash: {:016x}", fnv1a(config.to_toml().as_bytes()));
+        return Ok(());
+    }
+    Logger::init(&config).unwrap_or_else(|e| {
+        eprintln!("Configuration error: cannot open log file: {}", e);
+        std::process::exit(2);
+    });
+    panic::set_hook(Box::new(|info| log::error!("{}", info)));
+    signals::install();
+    let mut servers = vec![(Listener::Public, config.address.clone())];
+    if let Some(admin_address) = &config.admin_address {
+        servers.push((Listener::Admin, admin_address.clone()));
+    }
+    let servers: Vec<(Listener, Arc<tiny_http::Server>)> = servers.into_iter().map(|(listener, address)| {
+        let server = tiny_http::Server::http(&address).unwrap_or_else(|e| {
+            log::error!("Cannot listen on {}: {}", address, e);
+            std::process::exit(1);
+        });
+        log::info!("Listening on {} ({:?})", address, listener);
+        (listener, Arc::new(server))
+    }).collect();
+    if let Some(pid_file) = &config.pid_file {
+        std::fs::write(pid_file, format!("{}\n", std::process::id())).map_err(|e| {
+            log::error!("Cannot write PID file {}: {}", pid_file.display(), e);
+            e
+        })?;
+    }
+    let pid_file = config.pid_file.clone();
+    if std::env::var_os(NOTIFY_READY).is_some() { println!("{}", READY); }
+    // Forward requests from every listener to this thread.
+    let stopping = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+    let forwarders: Vec<_> = servers.iter().map(|(listener, server)| {
+        let (listener, server, stopping, sender) = (*listener, server.clone(), stopping.clone(), sender.clone());
+        std::thread::spawn(move || loop {
+            match server.recv() {
+                Ok(request) => { if sender.send((request, listener)).is_err() { break; } },
+                Err(_) if stopping.load(Ordering::SeqCst) => break,
+                Err(e) => log::warn!("Cannot accept a connection: {}", e),
+            }
+        })
+    }).collect();
+    // A second shutdown signal skips the drain grace period and the
+    // shutdown timeout.
+    let mut forced = false;
+    while !ocularity.is_drained() {
+        if signals::take_shutdown() {
+            if ocularity.is_draining() {
+                log::info!("Received a second shutdown signal; stopping now");
+                forced = true;
+                break;
+            }
+            log::info!("Received a shutdown signal; draining for {} seconds", ocularity.config().drain_grace);
+            ocularity.start_draining();
+        }
+        if signals::take_reload() {
+            let outcome = ocularity.reload();
+            log::info!("{}", outcome);
+        }
+        if let Ok((request, listener)) = receiver.recv_timeout(Duration::from_millis(100)) {
+            ocularity.respond(request, listener);
+        }
+    }
+    log::info!("No longer accepting requests");
+    stopping.store(true, Ordering::SeqCst);
+    for (_, server) in &servers { server.unblock(); }
+    for forwarder in forwarders { let _ = forwarder.join(); }
+    let timeout = if forced { 0 } else { ocularity.config().shutdown_timeout };
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    while Instant::now() < deadline {
+        match receiver.try_recv() {
+            Ok((request, listener)) => ocularity.respond(request, listener),
+            Err(_) => break,
+        }
+    }
+    drop(servers);
+    log::info!("Shut down");
+    log::logger().flush();
+    if let Some(pid_file) = pid_file { let _ = std::fs::remove_file(pid_file); }
+    Ok(())
 }
@@ -0,0 +1,122 @@
+//! Dispatching requests to handlers by method and path.
+
+use tiny_http::{Method};
+
+use crate::server::{HttpError, HttpOkay, HttpRequest, Listener};
+
+/// Who may use a route.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// A participant page; unavailable in maintenance mode.
+    Participant,
+    /// Always available, e.g. static files and health checks.
+    Infrastructure,
+    /// Requires the admin token, and is served only on the admin listener
+    /// if there is one.
+    Admin,
+}
+
+/// The parts of a request that a handler needs.
+pub struct Context<'a> {
+    pub request: &'a HttpRequest,
+    pub listener: Listener,
+    /// The path segments matched by `:name` and `*` in the route pattern,
+    /// in order. `*` matches the remaining segments joined by `/`.
+    pub captures: Vec<String>,
+}
+
+/// A function that handles the requests matching a route.
+pub type Handler<S> = fn(&mut S, &Context<'_>) -> Result<HttpOkay, HttpError>;
+
+/// One segment of a route pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// Matches exactly this segment.
+    Literal(String),
+    /// `:name`: matches any one segment.
+    Param,
+    /// `*`: matches all remaining segments, including none.
+    Rest,
+}
+
+/// A path pattern, the methods it accepts, and the handler it dispatches to.
+pub struct Route<S> {
+    pattern: Vec<Segment>,
+    methods: Vec<Method>,
+    pub access: Access,
+    pub handler: Handler<S>,
+}
+
+impl<S> Route<S> {
+    /// If `path` matches `self.pattern`, returns the captured segments.
+    fn matches(&self, path: &[String]) -> Option<Vec<String>> {
+        let mut captures = Vec::new();
+        let mut path = path.iter();
+        for segment in &self.pattern {
+            match segment {
+                Segment::Literal(literal) => { if path.next()? != literal { return None; } },
+                Segment::Param => { captures.push(path.next()?.clone()); },
+                Segment::Rest => {
+                    captures.push(path.by_ref().map(String::as_str).collect::<Vec<_>>().join("/"));
+                },
+            }
+        }
+        if path.next().is_some() { return None; }
+        Some(captures)
+    }
+}
+
+/// A table of [`Route`]s, tried in the order they were added.
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+}
+
+impl<S> Default for Router<S> {
+    fn default() -> Self { Router {routes: Vec::new()} }
+}
+
+impl<S> Router<S> {
+    pub fn new() -> Self { Self::default() }
+
+    /// Add a route for `methods` and `pattern`, e.g. `/static/:name`.
+    ///
+    /// A pattern segment `:name` matches any one path segment, and a final
+    /// `*` matches the rest of the path. Panics if `pattern` does not start
+    /// with `/` or has `*` anywhere but at the end.
+    pub fn route(mut self, methods: &[Method], pattern: &str, access: Access, handler: Handler<S>) -> Self {
+        let pattern = pattern.strip_prefix('/').expect("route patterns start with `/`");
+        let pattern: Vec<Segment> = pattern.split('/').filter(|s| !s.is_empty()).map(|s| match s {
+            "*" => Segment::Rest,
+            _ if s.starts_with(':') => Segment::Param,
+            _ => Segment::Literal(s.to_owned()),
+        }).collect();
+        assert!(
+            !pattern.iter().rev().skip(1).any(|s| *s == Segment::Rest),
+            "`*` may only end a route pattern",
+        );
+        self.routes.push(Route {pattern, methods: methods.to_vec(), access, handler});
+        self
+    }
+
+    /// Find the first route matching `method` and `path`, and the segments it
+    /// captures. A route that accepts GET also accepts HEAD; the server
+    /// then sends the headers without the body.
+    ///
+    /// If no route matches `path` the error is [`HttpError::NotFound`]. If
+    /// some do but none accepts `method` it is [`HttpError::MethodNotAllowed`],
+    /// listing the methods they accept.
+    pub fn find(&self, method: &Method, path: &[String]) -> Result<(&Route<S>, Vec<String>), HttpError> {
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in &self.routes {
+            if let Some(captures) = route.matches(path) {
+                let mut methods = route.methods.clone();
+                if methods.contains(&Method::Get) && !methods.contains(&Method::Head) { methods.push(Method::Head); }
+                if methods.contains(method) { return Ok((route, captures)); }
+                for m in methods {
+                    if !allowed.contains(&m) { allowed.push(m); }
+                }
+            }
+        }
+        if allowed.is_empty() { Err(HttpError::NotFound) } else { Err(HttpError::MethodNotAllowed(allowed)) }
+    }
+}
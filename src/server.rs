@@ -0,0 +1,1103 @@
+//! HTTP request handling.
+
+use std::collections::{HashMap};
+use std::collections::hash_map::{RandomState};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read};
+use std::error::{Error};
+use std::fs::{File, OpenOptions};
+use std::net::{IpAddr, Ipv6Addr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str::{FromStr};
+use std::sync::{Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tiny_http::{Method, Request, Response, ResponseBox, Header};
+
+use crate::config::{Config, ConfigError, ConfigSource};
+use crate::logging::{ACCESS, new_request_id, set_request_id};
+use crate::router::{Access, Context, Router};
+use crate::stats::{Stats};
+use crate::stimulus::{image};
+use crate::util::{constant_time_eq, decode_base64, escape_html, fnv1a, json_string, utc_timestamp};
+
+/// A successful HTTP response. All but `Response` are "200 OK".
+#[derive(Debug)]
+pub enum HttpOkay {
+    File(File),
+    Text(String),
+    Html(String),
+    Json(String),
+    Data(Vec<u8>),
+    /// Any other response, e.g. "204 No Content" or one with extra headers.
+    Response(HttpResponse),
+}
+
+/// A response with an arbitrary status, headers and body.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// A response with `status`, no headers and an empty body.
+    pub fn new(status: u16) -> Self {
+        HttpResponse {status, headers: Vec::new(), body: Vec::new()}
+    }
+
+    /// Add a header. Panics if `key` or `value` is not a valid header.
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push(header(key, value));
+        self
+    }
+
+    /// Set the body.
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Convert to a `tiny_http` response.
+    fn boxed(self) -> ResponseBox {
+        let mut response = Response::from_data(self.body).with_status_code(self.status);
+        for header in self.headers { response.add_header(header); }
+        response.boxed()
+    }
+}
+
+// An erroneous HTTP response.
+#[derive(Debug)]
+pub enum HttpError {
+    Invalid,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    /// The resource existed but has expired, e.g. a finished session.
+    Gone,
+    /// The path exists but does not accept the method; these are the
+    /// methods it does accept.
+    MethodNotAllowed(Vec<Method>),
+    /// The server is in maintenance mode; try again after this many seconds.
+    Unavailable(u64),
+    /// The URL is too long or has too many query parameters.
+    UriTooLong,
+    /// There are too many headers, or they are too long.
+    HeadersTooLarge,
+    /// The request body is too long.
+    PayloadTooLarge,
+    /// A query parameter is missing or has a bad value.
+    BadParam(ParamError),
+    /// Too many failed attempts; try again after this many seconds.
+    TooManyRequests(u64),
+    /// The server is not ready for participants; the JSON body says why.
+    NotReady(String),
+    Error(Box<dyn Error>),
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for HttpError {}
+
+impl HttpError {
+    /// The HTTP status code of this error.
+    pub fn status(&self) -> u16 {
+        match self {
+            HttpError::Invalid | HttpError::BadParam(_) => 400,
+            HttpError::Unauthorized => 401,
+            HttpError::Forbidden => 403,
+            HttpError::NotFound => 404,
+            HttpError::MethodNotAllowed(_) => 405,
+            HttpError::Gone => 410,
+            HttpError::PayloadTooLarge => 413,
+            HttpError::UriTooLong => 414,
+            HttpError::TooManyRequests(_) => 429,
+            HttpError::HeadersTooLarge => 431,
+            HttpError::Error(_) => 500,
+            HttpError::Unavailable(_) | HttpError::NotReady(_) => 503,
+        }
+    }
+
+    /// How many seconds the client should wait before trying again, if known.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            HttpError::Unavailable(seconds) | HttpError::TooManyRequests(seconds) => Some(*seconds),
+            _ => None,
+        }
+    }
+
+    /// The response to send for this error. Internal errors are not
+    /// described, so as not to reveal details of the server.
+    pub fn to_response(&self) -> HttpResponse {
+        let text = |body: &str| HttpResponse::new(self.status())
+            .header("Content-Type", "text/plain; charset=UTF-8")
+            .body(body);
+        let mut response = match self {
+            HttpError::Invalid => text("Invalid request"),
+            HttpError::BadParam(e) => text(&format!("Invalid request: {}", e)),
+            HttpError::Unauthorized => {
+                text("Unauthorized").header("WWW-Authenticate", "Basic realm=\"ocularity\"")
+            },
+            HttpError::Forbidden => text("Forbidden"),
+            HttpError::NotFound => text("Not found"),
+            HttpError::MethodNotAllowed(allowed) => {
+                let allowed: Vec<String> = allowed.iter().map(Method::to_string).collect();
+                text("Method not allowed").header("Allow", &allowed.join(", "))
+            },
+            HttpError::Gone => text("This page has expired"),
+            HttpError::PayloadTooLarge => text("Payload too large"),
+            HttpError::UriTooLong => text("URI too long"),
+            HttpError::TooManyRequests(_) => text("Too many requests"),
+            HttpError::HeadersTooLarge => text("Request header fields too large"),
+            HttpError::Error(_) => text("Internal error"),
+            HttpError::NotReady(json) => {
+                HttpResponse::new(503).header("Content-Type", "application/json").body(json.as_str())
+            },
+            HttpError::Unavailable(_) => {
+                HttpResponse::new(503).header("Content-Type", "text/html; charset=UTF-8").body(maintenance_page())
+            },
+        };
+        if let Some(seconds) = self.retry_after() {
+            response = response.header("Retry-After", &seconds.to_string());
+        }
+        response
+    }
+}
+
+macro_rules! impl_from_for_error {
+    ($e:ty) => {
+        impl From<$e> for HttpError {
+            fn from(e: $e) -> Self { HttpError::Error(e.into()) }
+        }
+    };
+}
+
+impl From<ParamError> for HttpError {
+    fn from(e: ParamError) -> Self { HttpError::BadParam(e) }
+}
+
+impl_from_for_error!(std::io::Error);
+impl_from_for_error!(std::num::ParseIntError);
+impl_from_for_error!(url::ParseError);
+impl_from_for_error!(png::EncodingError);
+
+fn header(key: &str, value: &str) -> tiny_http::Header {
+    let key_b = key.as_bytes();
+    let val_b = value.as_bytes();
+    Header::from_bytes(
+        key_b, val_b)
+        .unwrap() // callers pass valid header names and values
+}
+
+/// The best available identity of the client that sent a request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ClientInfo {
+    /// The client's IP address, if known.
+    pub ip: Option<IpAddr>,
+}
+
+impl ClientInfo {
+    /// Identify the client of `request`, which arrived on `listener`. See
+    /// [`Self::identify()`].
+    pub fn new(request: &Request, listener: Listener, trust_forwarded_for: bool) -> Self {
+        let forwarded_for = request.headers().iter().rev()
+            .find(|h| h.field.equiv("X-Forwarded-For"))
+            .map(|h| h.value.as_str());
+        Self::identify(request.remote_addr().map(|a| a.ip()), forwarded_for, listener, trust_forwarded_for)
+    }
+
+    /// Identify a client from the last address in `forwarded_for`, the
+    /// last `X-Forwarded-For` header, if `trust_forwarded_for`, otherwise
+    /// from `peer`, the socket peer.
+    ///
+    /// The header is never trusted on the admin listener, which is reached
+    /// directly rather than through the proxy, so that a client there cannot
+    /// choose its own address and evade the admin login backoff.
+    fn identify(peer: Option<IpAddr>, forwarded_for: Option<&str>, listener: Listener, trust_forwarded_for: bool) -> Self {
+        let forwarded = forwarded_for.filter(|_| trust_forwarded_for && listener == Listener::Public)
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        ClientInfo {ip: forwarded.or(peer)}
+    }
+}
+
+impl std::fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{}", ip),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Why a query parameter could not be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamError {
+    /// A required key was not given.
+    Missing(String),
+    /// A single-valued key was given more than once.
+    Repeated(String),
+    /// A value could not be parsed as the expected type.
+    Malformed {key: String, value: String, reason: String},
+}
+
+impl std::fmt::Display for ParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParamError::Missing(key) => write!(f, "missing parameter `{}`", key),
+            ParamError::Repeated(key) => write!(f, "repeated parameter `{}`", key),
+            ParamError::Malformed {key, value, reason} => {
+                write!(f, "parameter `{}` has invalid value `{}`: {}", key, value, reason)
+            },
+        }
+    }
+}
+
+impl Error for ParamError {}
+
+/// The query parameters of a request.
+///
+/// A key may be given more than once. The single-valued accessors reject
+/// repeated keys, so that appending a second value cannot silently change
+/// the value a handler sees. Keys that legitimately have several values
+/// should be read with [`Params::get_all()`].
+#[derive(Debug, Default)]
+pub struct Params(HashMap<String, Vec<String>>);
+
+impl Params {
+    /// Parse `value`, the value of `key`, as a `T`.
+    fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ParamError> where T::Err: std::fmt::Display {
+        value.parse().map_err(|e: T::Err| ParamError::Malformed {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            reason: e.to_string(),
+        })
+    }
+
+    /// Returns the value of `key`, which must be given exactly once.
+    pub fn get<T: FromStr>(&self, key: &str) -> Result<T, ParamError> where T::Err: std::fmt::Display {
+        self.get_opt(key)?.ok_or_else(|| ParamError::Missing(key.to_owned()))
+    }
+
+    /// Returns the value of `key` if it is given, which must be at most once.
+    pub fn get_opt<T: FromStr>(&self, key: &str) -> Result<Option<T>, ParamError> where T::Err: std::fmt::Display {
+        match self.0.get(key).map_or(&[][..], Vec::as_slice) {
+            [] => Ok(None),
+            [value] => Ok(Some(Self::parse(key, value)?)),
+            _ => {
+                log::warn!("Rejected repeated parameter `{}`", key);
+                Err(ParamError::Repeated(key.to_owned()))
+            },
+        }
+    }
+
+    /// Returns the value of `key`, or `default` if it is not given.
+    pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> Result<T, ParamError> where T::Err: std::fmt::Display {
+        Ok(self.get_opt(key)?.unwrap_or(default))
+    }
+
+    /// Returns all values of `key`, in the order given.
+    pub fn get_all<T: FromStr>(&self, key: &str) -> Result<Vec<T>, ParamError> where T::Err: std::fmt::Display {
+        self.0.get(key).map_or(&[][..], Vec::as_slice).iter().map(|value| Self::parse(key, value)).collect()
+    }
+}
+
+impl FromIterator<(String, String)> for Params {
+    fn from_iter<I: IntoIterator<Item=(String, String)>>(pairs: I) -> Self {
+        let mut ret = Params::default();
+        for (key, value) in pairs { ret.0.entry(key).or_default().push(value); }
+        ret
+    }
+}
+
+/// The parts of an HTTP request that the handlers use.
+///
+/// Handlers see only this, not the `tiny_http` request, so that they can be
+/// tested without a socket.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: Method,
+    /// The percent-decoded path segments. See [`canonical_path()`].
+    pub path: Vec<String>,
+    pub params: Params,
+    /// The fields of an `application/x-www-form-urlencoded` body.
+    pub form: Params,
+    /// The header names and values, in the order received.
+    pub headers: Vec<(String, String)>,
+    pub client: ClientInfo,
+}
+
+impl HttpRequest {
+    /// Parse a request for `url`. Reject it if it is pathologically large
+    /// or if its path is not canonical.
+    pub fn new(method: Method, url: &str, headers: Vec<(String, String)>, client: ClientInfo) -> Result<Self, HttpError> {
+        check_limits(url, &headers)?;
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let path = canonical_path(path)?;
+        let params: Params = url::form_urlencoded::parse(query.as_bytes()).map(
+            |(key, value)| (key.into_owned(), value.into_owned())
+        ).collect();
+        Ok(HttpRequest {method, path, params, form: Params::default(), headers, client})
+    }
+
+    /// Parse `body` as the fields of a form. Reject it if it is longer than
+    /// [`MAX_FORM_BYTES`].
+    pub fn with_form(mut self, body: &[u8]) -> Result<Self, HttpError> {
+        if body.len() > MAX_FORM_BYTES { return Err(HttpError::PayloadTooLarge); }
+        self.form = url::form_urlencoded::parse(body).map(
+            |(key, value)| (key.into_owned(), value.into_owned())
+        ).collect();
+        Ok(self)
+    }
+
+    /// Convert `request`, which came from `client`, reading its body if it
+    /// is a form.
+    pub fn from_tiny_http(request: &mut Request, client: ClientInfo) -> Result<Self, HttpError> {
+        let headers = request.headers().iter().map(
+            |h| (h.field.as_str().as_str().to_owned(), h.value.as_str().to_owned())
+        ).collect();
+        let ret = Self::new(request.method().clone(), request.url(), headers, client)?;
+        let is_form = ret.header("Content-Type").is_some_and(
+            |t| t.to_ascii_lowercase().starts_with("application/x-www-form-urlencoded")
+        );
+        if !is_form { return Ok(ret); }
+        let mut body = Vec::new();
+        request.as_reader().take(MAX_FORM_BYTES as u64 + 1).read_to_end(&mut body).map_err(|_| HttpError::Invalid)?;
+        ret.with_form(&body)
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Wrap `body` in the layout shared by all generated pages, with a heading
+/// `title`. `title` is escaped; `body` must already be HTML.
+fn page(title: &str, body: &str) -> String {
+    format!(
+        r##"<!DOCTYPE html>
+<html lang="en">
+ <head>
+  <title>{title}</title>
+  <link rel="stylesheet" href="/static/entireframework.min.css"/>
+  <style>
+   :focus-visible {{ outline: 3px solid #000; outline-offset: 2px; }}
+   .skip {{ position: absolute; left: -10000px; }}
+   .skip:focus {{ position: static; }}
+  </style>
+ </head>
+ <body>
+  <a class="skip" href="#main">Skip to content</a>
+  <main id="main">
+  <h1>{title}</h1>
+{body}  </main>
+ </body>
+</html>"##,
+        title = escape_html(title), body = body,
+    )
+}
+
+/// The number of failed admin logins allowed from an address before it is
+/// made to wait.
+const FREE_AUTH_FAILURES: u32 = 3;
+
+/// The longest time an address is made to wait after failed admin logins.
+const MAX_AUTH_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// The most client addresses whose failed admin logins are remembered.
+/// When there are more, the one that failed longest ago is forgotten.
+const MAX_AUTH_CLIENTS: usize = 4096;
+
+/// The client under which failed admin logins from `client` are counted.
+/// That is its address, except that all IPv6 addresses in a /64 count as
+/// one, since a single host can usually choose any of them.
+fn auth_client(client: ClientInfo) -> ClientInfo {
+    match client.ip {
+        Some(IpAddr::V6(ip)) if ip.to_ipv4_mapped().is_none() => {
+            ClientInfo {ip: Some(IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (!0 << 64))))}
+        },
+        _ => client,
+    }
+}
+
+/// The time an address must wait after `failures` failed admin logins.
+fn auth_backoff(failures: u32) -> Duration {
+    match failures.checked_sub(FREE_AUTH_FAILURES) {
+        None => Duration::ZERO,
+        Some(excess) => Duration::from_secs(1 << excess.min(12)).min(MAX_AUTH_BACKOFF),
+    }
+}
+
+/// The longest URL accepted, in bytes.
+const MAX_URL_LENGTH: usize = 2048;
+
+/// The most query parameters accepted.
+const MAX_PARAMS: usize = 32;
+
+/// The most headers accepted.
+const MAX_HEADERS: usize = 64;
+
+/// The longest total size of the headers accepted, in bytes.
+const MAX_HEADER_BYTES: usize = 8192;
+
+/// The longest form body accepted, in bytes.
+const MAX_FORM_BYTES: usize = 8192;
+
+/// Split the path of a request URL into percent-decoded segments.
+///
+/// Only canonical paths are accepted: the path must start with `/`, must not
+/// contain empty segments (e.g. `//` or a trailing `/`) or `.` or `..`
+/// segments, and no decoded segment may contain `/`, `\\` or NUL. The root
+/// path `/` has no segments.
+fn canonical_path(path: &str) -> Result<Vec<String>, HttpError> {
+    let path = path.strip_prefix('/').ok_or(HttpError::Invalid)?;
+    if path.is_empty() { return Ok(Vec::new()); }
+    path.split('/').map(|segment| {
+        let segment = url_escape::decode(segment);
+        if matches!(segment.as_ref(), "" | "." | "..") || segment.contains(['/', '\\', '\0']) {
+            return Err(HttpError::Invalid);
+        }
+        Ok(segment.into_owned())
+    }).collect()
+}
+
+/// Reject a request for `url` with `headers` if it is pathologically large,
+/// before parsing it.
+fn check_limits(url: &str, headers: &[(String, String)]) -> Result<(), HttpError> {
+    if url.len() > MAX_URL_LENGTH { return Err(HttpError::UriTooLong); }
+    let query = url.split_once('?').map_or("", |(_, query)| query);
+    if query.split('&').filter(|pair| !pair.is_empty()).count() > MAX_PARAMS { return Err(HttpError::UriTooLong); }
+    if headers.len() > MAX_HEADERS { return Err(HttpError::HeadersTooLarge); }
+    let header_bytes: usize = headers.iter().map(|(key, value)| key.len() + value.len() + 4).sum();
+    if header_bytes > MAX_HEADER_BYTES { return Err(HttpError::HeadersTooLarge); }
+    Ok(())
+}
+
+/// The page shown to participants in maintenance mode.
+fn maintenance_page() -> String {
+    page(
+        "Study temporarily paused",
+        "  <p>We are doing some maintenance. Please come back later.</p>\n",
+    )
+}
+
+/// Which listener a request arrived on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Listener {
+    /// The listener on `address`, for participants.
+    Public,
+    /// The listener on `admin_address`, for the admin pages.
+    Admin,
+}
+
+/// Builds an [`Ocularity`].
+///
+/// Settings are recorded as if given as command-line flags, so that they
+/// survive [`Ocularity::reload()`], and are checked by
+/// [`OcularityBuilder::build()`].
+#[derive(Debug, Default)]
+pub struct OcularityBuilder {
+    source: ConfigSource,
+}
+
+impl OcularityBuilder {
+    /// Read settings from `source`, replacing any given so far.
+    pub fn source(mut self, source: ConfigSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Read settings from the TOML file at `path`.
+    pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.source.path = Some(path.into());
+        self
+    }
+
+    /// Set the setting called `key` to `value`. See [`Config::set()`].
+    pub fn set(mut self, key: &str, value: &str) -> Self {
+        self.source.flags.push((format!("--{}", key.replace('_', "-")), value.to_owned()));
+        self
+    }
+
+    /// Do not read `OCULARITY_*` or `RUST_LOG`, e.g. so that tests do not
+    /// depend on the environment they run in.
+    pub fn ignore_env(mut self) -> Self {
+        self.source.ignore_env = true;
+        self
+    }
+
+    /// Listen for participants on `address`, as `host:port`.
+    pub fn address(self, address: &str) -> Self { self.set("address", address) }
+
+    /// Serve the admin pages only on `admin_address`, as `host:port`.
+    pub fn admin_address(self, admin_address: &str) -> Self { self.set("admin_address", admin_address) }
+
+    /// The URL at which the server is publicly visible.
+    pub fn base_url(self, base_url: &str) -> Self { self.set("base_url", base_url) }
+
+    /// Enable the admin pages, protected by `admin_token`.
+    pub fn admin_token(self, admin_token: &str) -> Self { self.set("admin_token", admin_token) }
+
+    /// Load the configuration and make the server, or explain what is wrong
+    /// with the settings.
+    pub fn build(self) -> Result<Ocularity, ConfigError> {
+        let config = self.source.load()?;
+        Ok(Ocularity::new(self.source, config))
+    }
+}
+
+/// The state of the server.
+pub struct Ocularity {
+    source: ConfigSource,
+    config: Config,
+    stats: Stats,
+    /// If `true`, participant routes are unavailable.
+    maintenance: bool,
+    /// When shutdown was requested, if it has been.
+    draining_since: Option<Instant>,
+    /// For each client with recent failed admin logins, the number
+    /// of failures and the time of the last one.
+    auth_failures: HashMap<ClientInfo, (u32, Instant)>,
+    /// The routes, shared so that a handler can borrow `self` mutably.
+    router: Arc<Router<Ocularity>>,
+    /// A random token that the dashboard's forms send back, and that a page
+    /// on another site cannot know.
+    csrf_token: String,
+}
+
+impl Ocularity {
+    /// Start building a server.
+    pub fn builder() -> OcularityBuilder { OcularityBuilder::default() }
+
+    fn new(source: ConfigSource, config: Config) -> Self {
+        Ocularity {
+            source, config,
+            stats: Stats::new(),
+            maintenance: false,
+            draining_since: None,
+            auth_failures: HashMap::new(),
+            router: Arc::new(Self::routes()),
+            csrf_token: new_csrf_token(),
+        }
+    }
+
+    /// The pages this server serves.
+    fn routes() -> Router<Ocularity> {
+        use Access::{Admin, Infrastructure, Participant};
+        let get = &[Method::Get];
+        let post = &[Method::Post];
+        Router::<Ocularity>::new()
+            .route(get, "/hello", Participant, |_, _| Ok(HttpOkay::Text("Hello, Martin!".to_owned())))
+            .route(get, "/image.png", Participant, |_, cx| image(&cx.request.params))
+            .route(get, "/static/:name", Infrastructure, |_, cx| static_file(&cx.captures[0]))
+            .route(get, "/version", Infrastructure, |o, _| Ok(HttpOkay::Json(o.version())))
+            .route(get, "/healthz", Infrastructure, |o, _| Ok(HttpOkay::Response(
+                HttpResponse::new(200)
+                    .header("Content-Type", "application/json")
+                    .header("Cache-Control", "no-store")
+                    .body(format!("{{\"status\": \"ok\", \"uptime_seconds\": {}}}", o.stats.uptime().as_secs()))
+            )))
+            .route(get, "/readyz", Infrastructure, |o, _| o.readyz())
+            .route(get, "/admin", Admin, |o, _| Ok(HttpOkay::Html(o.dashboard())))
+            // Actions are POST only, so that a link or an image on another
+            // site cannot trigger them. A form on another site can still
+            // POST, which `check_same_origin()` and `check_csrf_token()`
+            // refuse.
+            .route(post, "/admin/maintenance", Admin, Self::set_maintenance)
+            .route(post, "/admin/shutdown", Admin, Self::shutdown)
+            .route(post, "/admin/reload", Admin, |o, _| {
+                let outcome = o.reload();
+                log::info!("{}", outcome);
+                Ok(HttpOkay::Text(outcome))
+            })
+    }
+
+    /// The current configuration.
+    pub fn config(&self) -> &Config { &self.config }
+
+    /// Returns `true` if shutdown has been requested.
+    pub fn is_draining(&self) -> bool { self.draining_since.is_some() }
+
+    /// Returns `true` if shutdown has been requested and the drain grace
+    /// period has elapsed, i.e. if the server should stop accepting requests.
+    pub fn is_drained(&self) -> bool {
+        self.draining_since.is_some_and(
+            |since| since.elapsed() >= Duration::from_secs(self.config.drain_grace)
+        )
+    }
+
+    /// Stop reporting ready, and stop accepting requests once the drain
+    /// grace period has elapsed. See [`Self::is_drained()`].
+    pub fn start_draining(&mut self) {
+        if self.draining_since.is_none() { self.draining_since = Some(Instant::now()); }
+    }
+
+    /// Re-read the configuration from `self.source`, and describe the outcome.
+    /// If the new configuration is invalid, the old one is kept.
+    pub fn reload(&mut self) -> String {
+        match self.source.load() {
+            Ok(mut config) => {
+                let mut ret = "Reloaded configuration.".to_owned();
+                if config.address != self.config.address || config.admin_address != self.config.admin_address {
+                    ret.push_str(" Changing the addresses requires a restart.");
+                    config.address = self.config.address.clone();
+                    config.admin_address = self.config.admin_address.clone();
+                }
+                // The logger and the PID file are set up once, at startup.
+                if config.log_file != self.config.log_file
+                    || config.access_log != self.config.access_log
+                    || config.log_max_bytes != self.config.log_max_bytes
+                    || config.pid_file != self.config.pid_file
+                {
+                    ret.push_str(" Changing the log files or the PID file requires a restart.");
+                    config.log_file = self.config.log_file.clone();
+                    config.access_log = self.config.access_log.clone();
+                    config.log_max_bytes = self.config.log_max_bytes;
+                    config.pid_file = self.config.pid_file.clone();
+                }
+                for warning in &config.warnings { log::warn!("{}", warning); }
+                log::set_max_level(config.log_level);
+                self.config = config;
+                ret
+            },
+            Err(e) => format!("Configuration error: {}. Keeping the old configuration.", e),
+        }
+    }
+
+    /// Handle `request`, which arrived on `listener`, and send the response.
+    pub fn respond(&mut self, mut request: Request, listener: Listener) {
+        let id = new_request_id();
+        set_request_id(Some(&id));
+        let client = ClientInfo::new(&request, listener, self.config.trust_forwarded_for);
+        let result = HttpRequest::from_tiny_http(&mut request, client)
+            .and_then(|r| self.handle_request(&r, listener));
+        let response = match result {
+            Ok(HttpOkay::File(file)) => {
+                Response::from_file(file).boxed()
+            },
+            Ok(HttpOkay::Text(text)) => {
+                Response::from_string(text).boxed()
+            },
+            Ok(HttpOkay::Html(html)) => {
+                let header = header("Content-Type", "text/html; charset=UTF-8");
+                Response::from_string(html).with_header(header).boxed()
+            },
+            Ok(HttpOkay::Json(json)) => {
+                let header = header("Content-Type", "application/json");
+                Response::from_string(json).with_header(header).boxed()
+            },
+            Ok(HttpOkay::Data(data)) => {
+                let header = header("Content-Type", "image/png");
+                Response::from_data(data).with_header(header).boxed()
+            },
+            Ok(HttpOkay::Response(r)) => r.boxed(),
+            Err(e) => {
+                if let HttpError::Error(_) = e {
+                    log::error!("{}: {}", request.url(), e);
+                    self.stats.record_error(&id, &e);
+                }
+                e.to_response().boxed()
+            },
+        };
+        let response = response.with_header(header("X-Request-Id", &id));
+        let status = response.status_code().0;
+        self.stats.record_response(status);
+        log::info!(
+            target: ACCESS, "{} {} {} {}",
+            client, request.method(), request.url(), status,
+        );
+        request.respond(response).unwrap_or_else(|e2| log::warn!("IO Error: {}", e2));
+        set_request_id(None);
+    }
+
+    /// Dispatch `request`, which arrived on `listener`, to its route.
+    ///
+    /// A panic fails only this request, with an internal error. Handlers
+    /// must not leave `self` inconsistent if they panic.
+    pub(crate) fn handle_request(&mut self, request: &HttpRequest, listener: Listener) -> Result<HttpOkay, HttpError> {
+        panic::catch_unwind(AssertUnwindSafe(|| self.dispatch(request, listener))).unwrap_or_else(|payload| {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned());
+            Err(HttpError::Error(format!("Panic: {}", message).into()))
+        })
+    }
+
+    /// Dispatch `request`, which arrived on `listener`, to its route,
+    /// letting panics through.
+    fn dispatch(&mut self, request: &HttpRequest, listener: Listener) -> Result<HttpOkay, HttpError> {
+        log::debug!("{:?} {:?}", request.path, request.params);
+        let router = self.router.clone();
+        let (route, captures) = router.find(&request.method, &request.path)?;
+        if self.config.admin_address.is_some() {
+            // Serve the admin pages only on the admin listener, and no
+            // participant pages there.
+            let allowed = match listener {
+                Listener::Public => route.access != Access::Admin,
+                Listener::Admin => route.access != Access::Participant,
+            };
+            if !allowed { return Err(HttpError::NotFound); }
+        }
+        match route.access {
+            Access::Participant if self.maintenance => {
+                return Err(HttpError::Unavailable(self.config.maintenance_retry_after));
+            },
+            Access::Admin => {
+                self.check_admin(request)?;
+                // A bearer token is never sent implicitly, so only requests
+                // with basic credentials can be forged by another site.
+                let bearer = request.header("Authorization").is_some_and(|a| a.starts_with("Bearer "));
+                if !bearer && request.method != Method::Get && request.method != Method::Head {
+                    self.check_same_origin(request)?;
+                    self.check_csrf_token(request)?;
+                }
+            },
+            _ => {},
+        }
+        (route.handler)(self, &Context {request, listener, captures})
+    }
+
+    /// Check that `request` carries the admin token, either as a bearer
+    /// token or as the password of HTTP basic authentication.
+    ///
+    /// After [`FREE_AUTH_FAILURES`] wrong or malformed attempts from a
+    /// client address, further attempts are refused for a time that doubles
+    /// with each failure.
+    fn check_admin(&mut self, request: &HttpRequest) -> Result<(), HttpError> {
+        if self.config.admin_token.is_none() { return Err(HttpError::Forbidden); }
+        let client = auth_client(request.client);
+        if let Some(&(failures, last)) = self.auth_failures.get(&client) {
+            let wait = auth_backoff(failures).saturating_sub(last.elapsed());
+            if !wait.is_zero() { return Err(HttpError::TooManyRequests(wait.as_secs() + 1)); }
+        }
+        let result = self.check_authorization(request);
+        match result {
+            Ok(()) => { self.auth_failures.remove(&client); },
+            Err(_) if request.header("Authorization").is_some() => {
+                self.auth_failures.retain(|_, (_, last)| last.elapsed() < MAX_AUTH_BACKOFF);
+                let failures = self.auth_failures.get(&client).map_or(0, |&(failures, _)| failures) + 1;
+                if failures == 1 && self.auth_failures.len() >= MAX_AUTH_CLIENTS {
+                    let oldest = self.auth_failures.iter().min_by_key(|(_, &(_, last))| last).map(|(&c, _)| c);
+                    if let Some(oldest) = oldest { self.auth_failures.remove(&oldest); }
+                }
+                self.auth_failures.insert(client, (failures, Instant::now()));
+                log::warn!(
+                    "Admin authentication failed from {} ({} consecutive failures)",
+                    request.client, failures,
+                );
+            },
+            Err(_) => {},
+        }
+        result
+    }
+
+    /// Check the `Authorization` header of `request` against the admin token.
+    ///
+    /// A wrong basic password is [`HttpError::Unauthorized`], because a
+    /// browser asks for new credentials only when challenged, and would
+    /// otherwise keep sending the cached wrong ones. A wrong bearer token is
+    /// [`HttpError::Forbidden`].
+    fn check_authorization(&self, request: &HttpRequest) -> Result<(), HttpError> {
+        let admin_token = self.config.admin_token.as_ref().ok_or(HttpError::Forbidden)?;
+        let authorization = request.header("Authorization").ok_or(HttpError::Unauthorized)?;
+        let (token, wrong) = if let Some(token) = authorization.strip_prefix("Bearer ") {
+            (token.trim().as_bytes().to_owned(), HttpError::Forbidden)
+        } else if let Some(credentials) = authorization.strip_prefix("Basic ") {
+            let credentials = decode_base64(credentials.trim()).ok_or(HttpError::Invalid)?;
+            let colon = credentials.iter().position(|&c| c == b':').ok_or(HttpError::Invalid)?;
+            (credentials[colon + 1..].to_owned(), HttpError::Unauthorized)
+        } else {
+            return Err(HttpError::Unauthorized);
+        };
+        if !constant_time_eq(&token, admin_token.as_bytes()) { return Err(wrong); }
+        Ok(())
+    }
+
+    /// Check that `request`, an admin action, was not sent by a page on
+    /// another site, which a browser would do with its cached basic
+    /// credentials.
+    ///
+    /// Browsers say where a request comes from in `Sec-Fetch-Site`, or else
+    /// in `Origin`, which must then match `Host` or `base_url`. Requests
+    /// with neither header are refused.
+    fn check_same_origin(&self, request: &HttpRequest) -> Result<(), HttpError> {
+        let same_origin = if let Some(site) = request.header("Sec-Fetch-Site") {
+            site == "same-origin"
+        } else if let Some(origin) = request.header("Origin") {
+            let host = origin.split_once("://").map(|(_, host)| host);
+            (host.is_some() && host == request.header("Host"))
+                || origin == self.config.base_url.origin().ascii_serialization()
+        } else {
+            false
+        };
+        if !same_origin {
+            log::warn!("Refusing a cross-site admin request from {}", request.client);
+            return Err(HttpError::Forbidden);
+        }
+        Ok(())
+    }
+
+    /// Check that `request`, an admin action, came from a dashboard form,
+    /// i.e. that its form field `csrf_token` is `self.csrf_token`.
+    fn check_csrf_token(&self, request: &HttpRequest) -> Result<(), HttpError> {
+        let token = request.form.get_opt::<String>("csrf_token")?.unwrap_or_default();
+        if !constant_time_eq(token.as_bytes(), self.csrf_token.as_bytes()) {
+            log::warn!("Refusing an admin request without the form token from {}", request.client);
+            return Err(HttpError::Forbidden);
+        }
+        Ok(())
+    }
+
+    /// Report whether the server is ready for participant traffic, with the
+    /// outcome of each check as JSON.
+    fn readyz(&self) -> Result<HttpOkay, HttpError> {
+        let logs_writable = [&self.config.log_file, &self.config.access_log].into_iter().flatten().all(
+            |path| OpenOptions::new().append(true).open(path).is_ok()
+        );
+        let checks = [
+            ("logs_writable", logs_writable),
+            ("not_in_maintenance", !self.maintenance),
+            ("not_draining", self.draining_since.is_none()),
+        ];
+        let ready = checks.iter().all(|&(_, ok)| ok);
+        let checks: Vec<String> = checks.iter().map(|(name, ok)| format!("\"{}\": {}", name, ok)).collect();
+        let json = format!(
+            "{{\"status\": \"{}\", \"checks\": {{{}}}}}",
+            if ready { "ready" } else { "not ready" }, checks.join(", "),
+        );
+        if ready { Ok(HttpOkay::Json(json)) } else { Err(HttpError::NotReady(json)) }
+    }
+
+    /// Describe this build and its configuration as JSON.
+    fn version(&self) -> String {
+        let build_time: u64 = env!("OCULARITY_BUILD_TIME").parse().unwrap_or(0);
+        let build_time = UNIX_EPOCH + Duration::from_secs(build_time);
+        let features: Vec<String> = env!("OCULARITY_FEATURES").split(',')
+            .filter(|f| !f.is_empty())
+            .map(json_string)
+            .collect();
+        format!(
+            "{{\"version\": {}, \"commit\": {}, \"build_time\": {}, \"features\": [{}], \"config_hash\": \"{:016x}\"}}",
+            json_string(env!("CARGO_PKG_VERSION")),
+            json_string(env!("OCULARITY_GIT_COMMIT")),
+            json_string(&utc_timestamp(build_time)),
+            features.join(", "),
+            fnv1a(self.config.to_toml().as_bytes()),
+        )
+    }
+
+    /// Render the admin dashboard.
+    fn dashboard(&self) -> String {
+        let csrf_token = format!(r#"<input type="hidden" name="csrf_token" value="{}"/>"#, self.csrf_token);
+        let maintenance = if self.maintenance {
+            format!(r#"<form method="post" action="/admin/maintenance?on=0">{}<p><strong>Maintenance mode is on.</strong> <button>Resume the study</button></p></form>"#, csrf_token)
+        } else {
+            format!(r#"<form method="post" action="/admin/maintenance?on=1">{}<p><button>Pause the study for maintenance</button></p></form>"#, csrf_token)
+        };
+        page("Ocularity", &format!("  {}\n{}", maintenance, self.stats.to_html()))
+    }
+
+    /// Turn maintenance mode on or off, according to parameter `on`.
+    fn set_maintenance(&mut self, cx: &Context) -> Result<HttpOkay, HttpError> {
+        self.maintenance = match cx.request.params.get::<u8>("on")? {
+            1 => true,
+            0 => false,
+            _ => return Err(HttpError::Invalid),
+        };
+        let outcome = format!("Maintenance mode {}", if self.maintenance { "on" } else { "off" });
+        log::info!("{}", outcome);
+        Ok(HttpOkay::Text(outcome))
+    }
+
+    /// Start draining, after which the server shuts down.
+    fn shutdown(&mut self, _cx: &Context) -> Result<HttpOkay, HttpError> {
+        self.start_draining();
+        let outcome = format!("Shutting down after draining for {} seconds", self.config.drain_grace);
+        log::info!("{}", outcome);
+        Ok(HttpOkay::Text(outcome))
+    }
+}
+
+/// Generate a random token that another site cannot guess.
+fn new_csrf_token() -> String {
+    let random = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+        hasher.finish()
+    };
+    format!("{:016x}{:016x}", random(), random())
+}
+
+// ----------------------------------------------------------------------------
+
+/// The files in the working directory served under `/static/`. Nothing else
+/// there is served, since it may include the config file and the logs.
+pub const STATIC_FILES: &[&str] = &["entireframework.min.css", "question.html"];
+
+fn static_file(name: &str) -> Result<HttpOkay, HttpError> {
+    if !STATIC_FILES.contains(&name) { return Err(HttpError::NotFound); }
+    Ok(HttpOkay::File(File::open(Path::new(name)).map_err(|e| file_error(name, e))?))
+}
+
+/// Report a missing or unreadable file `name` as [`HttpError::NotFound`],
+/// rather than as an internal error, and other errors as they are.
+fn file_error(name: &str, e: std::io::Error) -> HttpError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => {
+            log::warn!("Cannot open {}: {}", name, e);
+            HttpError::NotFound
+        },
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        for failures in 0..FREE_AUTH_FAILURES { assert_eq!(auth_backoff(failures), Duration::ZERO); }
+        assert_eq!(auth_backoff(FREE_AUTH_FAILURES), Duration::from_secs(1));
+        assert_eq!(auth_backoff(FREE_AUTH_FAILURES + 1), Duration::from_secs(2));
+        assert_eq!(auth_backoff(FREE_AUTH_FAILURES + 5), Duration::from_secs(32));
+        assert_eq!(auth_backoff(FREE_AUTH_FAILURES + 12), MAX_AUTH_BACKOFF);
+        assert_eq!(auth_backoff(u32::MAX), MAX_AUTH_BACKOFF);
+    }
+
+    #[test]
+    fn client_identity() {
+        let ip = |ip: &str| Some(ip.parse().unwrap());
+        let peer = ip("192.0.2.1");
+        let identify = |forwarded_for, listener, trust| ClientInfo::identify(peer, forwarded_for, listener, trust).ip;
+        assert_eq!(identify(Some("198.51.100.1, 203.0.113.7"), Listener::Public, true), ip("203.0.113.7"));
+        assert_eq!(identify(Some("2001:db8::1"), Listener::Public, true), ip("2001:db8::1"));
+        assert_eq!(identify(Some("203.0.113.7"), Listener::Public, false), peer);
+        assert_eq!(identify(Some("203.0.113.7"), Listener::Admin, true), peer);
+        assert_eq!(identify(Some("unknown"), Listener::Public, true), peer);
+        assert_eq!(identify(None, Listener::Public, true), peer);
+        assert_eq!(ClientInfo::identify(None, None, Listener::Public, true).ip, None);
+    }
+
+    #[test]
+    fn auth_clients() {
+        let v6 = |ip: &str| ClientInfo {ip: Some(ip.parse().unwrap())};
+        assert_eq!(auth_client(v6("2001:db8:1:2:3:4:5:6")), v6("2001:db8:1:2::"));
+        assert_ne!(auth_client(v6("2001:db8:1:3::1")), v6("2001:db8:1:2::"));
+        assert_eq!(auth_client(v6("::ffff:192.0.2.1")), v6("::ffff:192.0.2.1"));
+        assert_eq!(auth_client(v6("192.0.2.1")), v6("192.0.2.1"));
+        assert_eq!(auth_client(ClientInfo {ip: None}), ClientInfo {ip: None});
+        // Failures from one /64 add up.
+        let mut server = Ocularity::builder().ignore_env().admin_token("test-admin-token-0123").build().unwrap();
+        let wrong = |client| HttpRequest::new(
+            Method::Get, "/admin", vec![("Authorization".to_owned(), "Bearer wrong".to_owned())], client,
+        ).unwrap();
+        for i in 1..=FREE_AUTH_FAILURES { assert_eq!(server.check_admin(&wrong(v6(&format!("2001:db8::{}", i)))).unwrap_err().status(), 403); }
+        assert_eq!(server.check_admin(&wrong(v6("2001:db8::ffff"))).unwrap_err().status(), 429);
+    }
+
+    #[test]
+    fn auth_clients_limit() {
+        let mut server = Ocularity::builder().ignore_env().admin_token("test-admin-token-0123").build().unwrap();
+        let client = |i: usize| ClientInfo {ip: Some(IpAddr::V4((0x0a000000 + i as u32).into()))};
+        let wrong = |i| HttpRequest::new(
+            Method::Get, "/admin", vec![("Authorization".to_owned(), "Bearer wrong".to_owned())], client(i),
+        ).unwrap();
+        for _ in 0..FREE_AUTH_FAILURES { let _ = server.check_admin(&wrong(0)); }
+        for i in 1..MAX_AUTH_CLIENTS + 10 { let _ = server.check_admin(&wrong(i)); }
+        assert_eq!(server.auth_failures.len(), MAX_AUTH_CLIENTS);
+        // The client that failed longest ago is forgotten first.
+        assert!(!server.auth_failures.contains_key(&client(0)));
+        assert!(server.auth_failures.contains_key(&client(MAX_AUTH_CLIENTS + 9)));
+    }
+
+    #[test]
+    fn panicking_route() {
+        let mut server = Ocularity::builder().ignore_env().build().unwrap();
+        server.router = Arc::new(Router::new()
+            .route(&[Method::Get], "/panic", Access::Participant, |_, _| panic!("boom"))
+            .route(&[Method::Get], "/hello", Access::Participant, |_, _| Ok(HttpOkay::Text("Hello".to_owned())))
+        );
+        let get = |server: &mut Ocularity, url| {
+            let request = HttpRequest::new(Method::Get, url, vec![], ClientInfo {ip: None}).unwrap();
+            server.handle_request(&request, Listener::Public)
+        };
+        let result = get(&mut server, "/panic");
+        assert!(matches!(&result, Err(HttpError::Error(e)) if e.to_string() == "Panic: boom"), "{:?}", result);
+        assert_eq!(result.unwrap_err().status(), 500);
+        assert!(matches!(get(&mut server, "/hello"), Ok(HttpOkay::Text(text)) if text == "Hello"));
+    }
+
+    fn params(query: &str) -> Params {
+        url::form_urlencoded::parse(query.as_bytes()).map(|(k, v)| (k.into_owned(), v.into_owned())).collect()
+    }
+
+    #[test]
+    fn repeated_params() {
+        let params = params("a=1&b=2&b=3&c=x&e=");
+        assert_eq!(params.get::<u8>("a"), Ok(1));
+        assert_eq!(params.get::<u8>("b"), Err(ParamError::Repeated("b".to_owned())));
+        assert_eq!(params.get_opt::<u8>("b"), Err(ParamError::Repeated("b".to_owned())));
+        assert_eq!(params.get_or::<u8>("b", 0), Err(ParamError::Repeated("b".to_owned())));
+        assert_eq!(params.get_all::<u8>("b"), Ok(vec![2, 3]));
+        assert_eq!(params.get_all::<u8>("d"), Ok(vec![]));
+        assert_eq!(params.get::<u8>("d"), Err(ParamError::Missing("d".to_owned())));
+        assert_eq!(params.get_opt::<u8>("d"), Ok(None));
+        assert_eq!(params.get_or::<u8>("d", 7), Ok(7));
+        assert_eq!(params.get::<String>("e"), Ok(String::new()));
+        assert!(matches!(params.get::<u8>("c"), Err(ParamError::Malformed {key, value, ..}) if key == "c" && value == "x"));
+    }
+
+    #[test]
+    fn limits() {
+        let header = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+        assert!(check_limits(&format!("/{}", "a".repeat(MAX_URL_LENGTH - 1)), &[]).is_ok());
+        assert!(matches!(check_limits(&format!("/{}", "a".repeat(MAX_URL_LENGTH)), &[]), Err(HttpError::UriTooLong)));
+        let query = |n: usize| format!("/?{}", vec!["x=1"; n].join("&"));
+        assert!(check_limits(&query(MAX_PARAMS), &[]).is_ok());
+        assert!(matches!(check_limits(&query(MAX_PARAMS + 1), &[]), Err(HttpError::UriTooLong)));
+        // Empty pairs, as in `?&&a=1&`, are not counted.
+        assert!(check_limits(&format!("/?{}a=1", "&".repeat(100)), &[]).is_ok());
+        let headers = vec![header("X", "1"); MAX_HEADERS];
+        assert!(check_limits("/", &headers).is_ok());
+        let headers = vec![header("X", "1"); MAX_HEADERS + 1];
+        assert!(matches!(check_limits("/", &headers), Err(HttpError::HeadersTooLarge)));
+        let headers = [header("X", &"a".repeat(MAX_HEADER_BYTES))];
+        assert!(matches!(check_limits("/", &headers), Err(HttpError::HeadersTooLarge)));
+        let form = |body: &[u8]| HttpRequest::new(Method::Post, "/", vec![], ClientInfo {ip: None}).unwrap().with_form(body);
+        assert!(form(&[b'a'; MAX_FORM_BYTES]).is_ok());
+        assert!(matches!(form(&[b'a'; MAX_FORM_BYTES + 1]), Err(HttpError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn canonical_paths() {
+        let ok = |path: &str| canonical_path(path).unwrap();
+        assert_eq!(ok("/"), Vec::<String>::new());
+        assert_eq!(ok("/static/question.html"), ["static", "question.html"]);
+        assert_eq!(ok("/a%20b/caf%C3%A9/x+y"), ["a b", "café", "x+y"]);
+        assert_eq!(ok("/..a/a.."), ["..a", "a.."]);
+        for path in [
+            "", "static", "//", "/a//b", "/a/", "/./a", "/a/..", "/a/%2e%2E", "/%2E",
+            "/a%2fb", "/a%2Fb", "/a%5cb", "/a%5Cb", "/a\\b", "/a%00b", "/%00",
+        ] {
+            assert!(matches!(canonical_path(path), Err(HttpError::Invalid)), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn file_errors() {
+        use std::io::{Error, ErrorKind};
+        assert_eq!(file_error("x", Error::from(ErrorKind::NotFound)).status(), 404);
+        assert_eq!(file_error("x", Error::from(ErrorKind::PermissionDenied)).status(), 404);
+        assert_eq!(file_error("x", Error::from(ErrorKind::InvalidData)).status(), 500);
+    }
+}
@@ -0,0 +1,44 @@
+//! Unix signals that ask the server to shut down or to reload.
+//!
+//! The handlers only set flags, which the serving loop polls.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SIGHUP: i32 = 1;
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+/// Set when SIGTERM or SIGINT arrives.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Set when SIGHUP arrives.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    /// From the C library, which `std` already links.
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+extern "C" fn on_signal(signum: i32) {
+    let flag = if signum == SIGHUP { &RELOAD } else { &SHUTDOWN };
+    flag.store(true, Ordering::SeqCst);
+}
+
+/// Catch SIGTERM, SIGINT and SIGHUP, instead of dying at once.
+pub fn install() {
+    for signum in [SIGHUP, SIGINT, SIGTERM] {
+        // SAFETY: `on_signal` only stores to an atomic, which is
+        // async-signal-safe.
+        unsafe { signal(signum, on_signal); }
+    }
+}
+
+/// Returns `true` if SIGTERM or SIGINT has arrived since the last call.
+pub fn take_shutdown() -> bool {
+    SHUTDOWN.swap(false, Ordering::SeqCst)
+}
+
+/// Returns `true` if SIGHUP has arrived since the last call.
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
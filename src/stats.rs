@@ -0,0 +1,115 @@
+//! Counters for the admin dashboard.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::server::{HttpError};
+use crate::util::{escape_html, utc_timestamp};
+
+/// The number of internal errors remembered for the admin dashboard.
+const MAX_RECENT_ERRORS: usize = 20;
+
+/// Counters shown on the admin dashboard.
+#[derive(Debug)]
+pub struct Stats {
+    /// When the server started.
+    started: Instant,
+    /// The number of responses sent, by status code.
+    statuses: BTreeMap<u16, u64>,
+    /// For each minute of the hour, the minute since `started` that it was
+    /// last used for, and the number of responses in that minute.
+    last_hour: [(u64, u64); 60],
+    /// The most recent internal errors and their request IDs, oldest first.
+    errors: VecDeque<(SystemTime, String, String)>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            statuses: BTreeMap::new(),
+            last_hour: [(0, 0); 60],
+            errors: VecDeque::new(),
+        }
+    }
+
+    /// How long the server has been running.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Count a response with status `status`.
+    pub fn record_response(&mut self, status: u16) {
+        *self.statuses.entry(status).or_insert(0) += 1;
+        self.count_response(self.minute());
+    }
+
+    /// The number of whole minutes since the server started.
+    fn minute(&self) -> u64 { self.started.elapsed().as_secs() / 60 }
+
+    /// Count a response in `minute`.
+    fn count_response(&mut self, minute: u64) {
+        let bucket = &mut self.last_hour[(minute % 60) as usize];
+        if bucket.0 != minute { *bucket = (minute, 0); }
+        bucket.1 += 1;
+    }
+
+    /// The number of responses in the hour up to and including `minute`.
+    fn responses_in_hour_to(&self, minute: u64) -> u64 {
+        self.last_hour.iter().filter(|&&(m, _)| m <= minute && minute - m < 60).map(|&(_, count)| count).sum()
+    }
+
+    /// Remember an internal error in the request with ID `id`.
+    pub fn record_error(&mut self, id: &str, e: &HttpError) {
+        if self.errors.len() >= MAX_RECENT_ERRORS { self.errors.pop_front(); }
+        self.errors.push_back((SystemTime::now(), id.to_owned(), e.to_string()));
+    }
+
+    /// Render the statistics as part of the admin dashboard.
+    pub fn to_html(&self) -> String {
+        let uptime = self.started.elapsed().as_secs();
+        let mut statuses = String::new();
+        for (status, count) in &self.statuses {
+            statuses.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>", status, count));
+        }
+        let mut errors = String::new();
+        for (time, id, e) in self.errors.iter().rev() {
+            errors.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                utc_timestamp(*time), id, escape_html(e),
+            ));
+        }
+        format!(
+            r#"  <p>Up for {}d {:02}h {:02}m {:02}s; {} requests in the last hour.</p>
+  <h2>Responses</h2>
+  <table aria-label="Responses by status"><tr><th scope="col">Status</th><th scope="col">Count</th></tr>{}</table>
+  <h2>Recent errors</h2>
+  <table aria-label="Recent errors"><tr><th scope="col">Time</th><th scope="col">Request</th><th scope="col">Error</th></tr>{}</table>
+"#,
+            uptime / 86400, uptime / 3600 % 24, uptime / 60 % 60, uptime % 60,
+            self.responses_in_hour_to(self.minute()), statuses, errors,
+        )
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_in_last_hour() {
+        let mut stats = Stats::new();
+        for minute in [0, 0, 5, 59] { stats.count_response(minute); }
+        assert_eq!(stats.responses_in_hour_to(59), 4);
+        // Minute 0 is now more than an hour ago, and its bucket is reused.
+        for minute in [60, 61] { stats.count_response(minute); }
+        assert_eq!(stats.responses_in_hour_to(61), 4);
+        stats.count_response(130);
+        assert_eq!(stats.responses_in_hour_to(130), 1);
+        assert_eq!(stats.responses_in_hour_to(500), 0);
+    }
+}
@@ -0,0 +1,17 @@
+//! Stimulus images.
+
+use crate::server::{HttpOkay, HttpError, Params};
+
+/// Render a 1x1 PNG image of the colour given by parameters `r`, `g`, `b`.
+pub fn image(params: &Params) -> Result<HttpOkay, HttpError> {
+    let r: u8 = params.get("r")?;
+    let g: u8 = params.get("g")?;
+    let b: u8 = params.get("b")?;
+    let mut buf: Vec<u8> = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buf, 1, 1);
+    encoder.set_color(png::ColorType::Rgb);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&[r, g, b])?;
+    writer.finish()?;
+    Ok(HttpOkay::Data(buf))
+}
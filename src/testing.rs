@@ -0,0 +1,343 @@
+//! Helpers for testing request handling without a socket, and the tests
+//! that use them.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use tiny_http::{Method};
+
+use crate::config::{Config};
+use crate::router::{Access, Router};
+use crate::server::{ClientInfo, HttpError, HttpOkay, HttpRequest, Listener, Ocularity, OcularityBuilder};
+
+/// The admin token of [`server()`].
+pub const ADMIN_TOKEN: &str = "test-admin-token-0123";
+
+/// An `Authorization` header with [`ADMIN_TOKEN`] as a basic password.
+pub const BASIC: &str = "Basic YWRtaW46dGVzdC1hZG1pbi10b2tlbi0wMTIz";
+
+/// The client that [`request()`] comes from.
+pub const CLIENT: ClientInfo = ClientInfo {ip: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))};
+
+/// A builder that ignores the environment, so that tests do not depend on
+/// where they run.
+pub fn builder() -> OcularityBuilder {
+    Ocularity::builder().ignore_env()
+}
+
+/// A server with the default configuration, except that the admin token is
+/// [`ADMIN_TOKEN`].
+pub fn server() -> Ocularity {
+    builder().admin_token(ADMIN_TOKEN).build().unwrap()
+}
+
+/// A request for `url` from [`CLIENT`], with `headers`.
+pub fn request(method: Method, url: &str, headers: &[(&str, &str)]) -> Result<HttpRequest, HttpError> {
+    let headers = headers.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect();
+    HttpRequest::new(method, url, headers, CLIENT)
+}
+
+/// Send `request` to `server` on `listener`.
+pub fn send(server: &mut Ocularity, request: Result<HttpRequest, HttpError>, listener: Listener) -> Result<HttpOkay, HttpError> {
+    server.handle_request(&request?, listener)
+}
+
+/// Send a GET request for `url` to `server` on the public listener.
+pub fn get(server: &mut Ocularity, url: &str) -> Result<HttpOkay, HttpError> {
+    send(server, request(Method::Get, url, &[]), Listener::Public)
+}
+
+/// Send a GET request for `url` to `server` with the admin token.
+pub fn get_admin(server: &mut Ocularity, url: &str) -> Result<HttpOkay, HttpError> {
+    let authorization = format!("Bearer {}", ADMIN_TOKEN);
+    send(server, request(Method::Get, url, &[("Authorization", &authorization)]), Listener::Public)
+}
+
+/// Send a POST request for `url` to `server` with the admin token.
+pub fn post_admin(server: &mut Ocularity, url: &str) -> Result<HttpOkay, HttpError> {
+    let authorization = format!("Bearer {}", ADMIN_TOKEN);
+    send(server, request(Method::Post, url, &[("Authorization", &authorization)]), Listener::Public)
+}
+
+/// The form token in the admin dashboard of `server`.
+pub fn csrf_token(server: &mut Ocularity) -> String {
+    let Ok(HttpOkay::Html(html)) = get_admin(server, "/admin") else { panic!("expected the dashboard") };
+    let (_, rest) = html.split_once(r#"name="csrf_token" value=""#).expect("the dashboard has a form token");
+    rest[..rest.find('"').unwrap()].to_owned()
+}
+
+/// The status code that `result` would be sent with.
+pub fn status(result: &Result<HttpOkay, HttpError>) -> u16 {
+    match result {
+        Ok(HttpOkay::Response(response)) => response.status,
+        Ok(_) => 200,
+        Err(e) => e.status(),
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[test]
+fn hello() {
+    let mut server = server();
+    assert!(matches!(get(&mut server, "/hello"), Ok(HttpOkay::Text(text)) if text == "Hello, Martin!"));
+}
+
+#[test]
+fn unknown_path() {
+    let mut server = server();
+    assert_eq!(status(&get(&mut server, "/")), 404);
+    assert_eq!(status(&get(&mut server, "/goodbye")), 404);
+    assert_eq!(status(&get(&mut server, "/hello/world")), 404);
+}
+
+#[test]
+fn non_canonical_path() {
+    let mut server = server();
+    for url in ["hello", "//hello", "/hello/", "/./hello", "/static/..", "/static/%2e%2e", "/static/a%2fb"] {
+        assert_eq!(status(&get(&mut server, url)), 400, "{}", url);
+    }
+}
+
+#[test]
+fn method_not_allowed() {
+    let mut server = server();
+    let result = send(&mut server, request(Method::Post, "/hello", &[]), Listener::Public);
+    assert!(matches!(result, Err(HttpError::MethodNotAllowed(allowed)) if allowed == [Method::Get, Method::Head]));
+}
+
+#[test]
+fn head() {
+    let mut server = server();
+    for url in ["/hello", "/healthz", "/static/question.html"] {
+        assert_eq!(status(&send(&mut server, request(Method::Head, url, &[]), Listener::Public)), 200, "{}", url);
+    }
+    let result = send(&mut server, request(Method::Head, "/admin/reload", &[]), Listener::Public);
+    assert!(matches!(result, Err(HttpError::MethodNotAllowed(allowed)) if allowed == [Method::Post]));
+}
+
+#[test]
+fn limits() {
+    let mut server = server();
+    let long = format!("/hello?x={}", "a".repeat(3000));
+    assert_eq!(status(&get(&mut server, &long)), 414);
+    let many = format!("/hello?{}", vec!["x=1"; 40].join("&"));
+    assert_eq!(status(&get(&mut server, &many)), 414);
+    let headers = vec![("X-Padding", "a"); 100];
+    assert_eq!(status(&send(&mut server, request(Method::Get, "/hello", &headers), Listener::Public)), 431);
+}
+
+#[test]
+fn image_params() {
+    let mut server = server();
+    assert!(matches!(get(&mut server, "/image.png?r=1&g=2&b=3"), Ok(HttpOkay::Data(_))));
+    assert_eq!(status(&get(&mut server, "/image.png?r=1&g=2")), 400);
+    assert_eq!(status(&get(&mut server, "/image.png?r=1&g=2&b=256")), 400);
+    assert_eq!(status(&get(&mut server, "/image.png?r=1&r=9&g=2&b=3")), 400);
+}
+
+#[test]
+fn static_files() {
+    let mut server = server();
+    assert!(matches!(get(&mut server, "/static/question.html"), Ok(HttpOkay::File(_))));
+    for name in [".hidden", "ocularity.toml", "ocularity.log", "Cargo.toml", "nonexistent"] {
+        assert_eq!(status(&get(&mut server, &format!("/static/{}", name))), 404, "{}", name);
+    }
+}
+
+#[test]
+fn maintenance() {
+    let mut server = server();
+    assert_eq!(status(&get_admin(&mut server, "/admin/maintenance?on=1")), 405);
+    assert_eq!(status(&get(&mut server, "/hello")), 200);
+    assert_eq!(status(&post_admin(&mut server, "/admin/maintenance?on=1")), 200);
+    let result = get(&mut server, "/hello");
+    assert_eq!(status(&result), 503);
+    assert_eq!(result.unwrap_err().retry_after(), Some(Config::default().maintenance_retry_after));
+    assert_eq!(status(&get(&mut server, "/healthz")), 200);
+    assert_eq!(status(&get(&mut server, "/readyz")), 503);
+    assert_eq!(status(&post_admin(&mut server, "/admin/maintenance?on=0")), 200);
+    assert_eq!(status(&get(&mut server, "/hello")), 200);
+}
+
+#[test]
+fn admin_authorization() {
+    let mut server = server();
+    let with = |server: &mut Ocularity, authorization: &str| status(&send(
+        server, request(Method::Get, "/admin", &[("Authorization", authorization)]), Listener::Public,
+    ));
+    assert_eq!(status(&get(&mut server, "/admin")), 401);
+    assert_eq!(status(&get_admin(&mut server, "/admin")), 200);
+    assert_eq!(with(&mut server, BASIC), 200);
+    assert_eq!(with(&mut server, "Basic bm90IGJhc2U2NA"), 400);
+    assert_eq!(with(&mut server, "Bearer wrong"), 403);
+    // Reset the failures, before they are backed off.
+    assert_eq!(status(&get_admin(&mut server, "/admin")), 200);
+    assert_eq!(with(&mut server, "Digest whatever"), 401);
+    // A wrong basic password is challenged again, so that a browser asks
+    // for a new one.
+    let result = send(&mut server, request(Method::Get, "/admin", &[("Authorization", "Basic YWRtaW46d3Jvbmc=")]), Listener::Public);
+    let response = result.unwrap_err().to_response();
+    assert_eq!(response.status, 401);
+    assert!(response.headers.iter().any(|h| h.field.equiv("WWW-Authenticate")));
+}
+
+#[test]
+fn admin_actions_need_post() {
+    let mut server = server();
+    for url in ["/admin/maintenance?on=1", "/admin/shutdown", "/admin/reload"] {
+        let result = get_admin(&mut server, url);
+        assert!(matches!(result, Err(HttpError::MethodNotAllowed(allowed)) if allowed == [Method::Post]), "{}", url);
+    }
+    assert!(!server.is_drained());
+    assert_eq!(status(&post_admin(&mut server, "/admin/shutdown")), 200);
+    assert_eq!(status(&get(&mut server, "/readyz")), 503);
+}
+
+#[test]
+fn admin_actions_same_origin() {
+    let mut server = server();
+    let form = format!("csrf_token={}", csrf_token(&mut server));
+    let post = |server: &mut Ocularity, headers: &[(&str, &str)]| {
+        let mut headers = headers.to_vec();
+        headers.push(("Authorization", BASIC));
+        let request = request(Method::Post, "/admin/maintenance?on=1", &headers);
+        status(&send(server, request.and_then(|r| r.with_form(form.as_bytes())), Listener::Public))
+    };
+    assert_eq!(post(&mut server, &[("Sec-Fetch-Site", "cross-site"), ("Origin", "https://evil.example")]), 403);
+    assert_eq!(post(&mut server, &[("Sec-Fetch-Site", "same-site")]), 403);
+    assert_eq!(post(&mut server, &[("Origin", "https://evil.example"), ("Host", "127.0.0.1:8081")]), 403);
+    assert_eq!(post(&mut server, &[("Origin", "null"), ("Host", "127.0.0.1:8081")]), 403);
+    assert_eq!(post(&mut server, &[]), 403);
+    assert_eq!(status(&get(&mut server, "/hello")), 200);
+    assert_eq!(post(&mut server, &[("Sec-Fetch-Site", "same-origin")]), 200);
+    assert_eq!(status(&get(&mut server, "/hello")), 503);
+    assert_eq!(post(&mut server, &[("Origin", "http://127.0.0.1:8081"), ("Host", "127.0.0.1:8081")]), 200);
+    assert_eq!(post(&mut server, &[("Origin", "https://www.minworks.co.uk")]), 200);
+    // A bearer token is never sent implicitly.
+    assert_eq!(status(&post_admin(&mut server, "/admin/maintenance?on=0")), 200);
+}
+
+#[test]
+fn admin_actions_csrf_token() {
+    let mut server = server();
+    let token = csrf_token(&mut server);
+    assert_eq!(token.len(), 32);
+    assert_ne!(token, csrf_token(&mut self::server()));
+    let post = |server: &mut Ocularity, form: &str| {
+        let request = request(Method::Post, "/admin/maintenance?on=1", &[("Authorization", BASIC), ("Sec-Fetch-Site", "same-origin")]);
+        status(&send(server, request.and_then(|r| r.with_form(form.as_bytes())), Listener::Public))
+    };
+    assert_eq!(post(&mut server, ""), 403);
+    assert_eq!(post(&mut server, "csrf_token=wrong"), 403);
+    assert_eq!(post(&mut server, &format!("csrf_token={}&csrf_token={}", token, token)), 400);
+    assert_eq!(status(&get(&mut server, "/hello")), 200);
+    assert_eq!(post(&mut server, &format!("csrf_token={}", token)), 200);
+    assert_eq!(status(&get(&mut server, "/hello")), 503);
+}
+
+#[test]
+fn admin_disabled() {
+    let mut server = builder().build().unwrap();
+    assert_eq!(status(&get_admin(&mut server, "/admin")), 403);
+}
+
+#[test]
+fn admin_backoff() {
+    let mut server = server();
+    let wrong = |authorization| request(Method::Get, "/admin", &[("Authorization", authorization)]);
+    assert_eq!(status(&send(&mut server, wrong("Bearer wrong"), Listener::Public)), 403);
+    assert_eq!(status(&send(&mut server, wrong("Basic YWRtaW46d3Jvbmc="), Listener::Public)), 401);
+    assert_eq!(status(&send(&mut server, wrong("Bearer wrong"), Listener::Public)), 403);
+    let result = send(&mut server, wrong("Bearer wrong"), Listener::Public);
+    assert_eq!(status(&result), 429);
+    assert!(result.unwrap_err().retry_after().is_some());
+    // Even the right token must wait.
+    assert_eq!(status(&get_admin(&mut server, "/admin")), 429);
+    // Other clients are not affected.
+    let other = ClientInfo {ip: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)))};
+    let authorization = format!("Bearer {}", ADMIN_TOKEN);
+    let request = HttpRequest::new(Method::Get, "/admin", vec![("Authorization".to_owned(), authorization)], other);
+    assert_eq!(status(&send(&mut server, request, Listener::Public)), 200);
+}
+
+#[test]
+fn admin_listener() {
+    let mut server = builder()
+        .admin_token(ADMIN_TOKEN)
+        .admin_address("127.0.0.1:8001")
+        .build().unwrap();
+    let authorization = format!("Bearer {}", ADMIN_TOKEN);
+    let on = |server: &mut Ocularity, url: &str, listener: Listener| status(
+        &send(server, request(Method::Get, url, &[("Authorization", &authorization)]), listener)
+    );
+    assert_eq!(on(&mut server, "/admin", Listener::Public), 404);
+    assert_eq!(on(&mut server, "/admin", Listener::Admin), 200);
+    assert_eq!(on(&mut server, "/hello", Listener::Public), 200);
+    assert_eq!(on(&mut server, "/hello", Listener::Admin), 404);
+    assert_eq!(on(&mut server, "/healthz", Listener::Admin), 200);
+}
+
+#[test]
+fn readyz_checks() {
+    let mut server = server();
+    let Ok(HttpOkay::Json(json)) = get(&mut server, "/readyz") else { panic!("expected ready") };
+    assert_eq!(
+        json,
+        r#"{"status": "ready", "checks": {"logs_writable": true, "not_in_maintenance": true, "not_draining": true}}"#,
+    );
+    assert_eq!(status(&post_admin(&mut server, "/admin/maintenance?on=1")), 200);
+    let Err(HttpError::NotReady(json)) = get(&mut server, "/readyz") else { panic!("expected not ready") };
+    assert!(json.contains(r#""status": "not ready""#) && json.contains(r#""not_in_maintenance": false"#), "{}", json);
+}
+
+#[test]
+fn healthz_not_cached() {
+    let mut server = server();
+    let Ok(HttpOkay::Response(response)) = get(&mut server, "/healthz") else { panic!("expected a response") };
+    let cache_control = response.headers.iter().find(|h| h.field.equiv("Cache-Control"));
+    assert_eq!(cache_control.map(|h| h.value.as_str()), Some("no-store"));
+}
+
+#[test]
+fn builder_rejects_bad_settings() {
+    assert!(builder().address("localhost").build().is_err());
+    assert!(builder().base_url("ftp://example.com/").build().is_err());
+    assert!(builder().set("no_such_setting", "1").build().is_err());
+    let server = builder().address("0.0.0.0:80").base_url("https://example.com/study/").build().unwrap();
+    assert_eq!(server.config().address, "0.0.0.0:80");
+    assert_eq!(server.config().base_url.as_str(), "https://example.com/study/");
+}
+
+#[test]
+fn reload_keeps_startup_settings() {
+    let path = std::env::temp_dir().join(format!("ocularity-test-{}.toml", std::process::id()));
+    std::fs::write(&path, "drain_grace = 5\n").unwrap();
+    let mut server = builder().config_file(&path).build().unwrap();
+    std::fs::write(&path, "drain_grace = 7\nlog_file = \"other.log\"\naddress = \"127.0.0.1:9999\"\n").unwrap();
+    let outcome = server.reload();
+    std::fs::write(&path, "drain_grace = oops\n").unwrap();
+    let failed = server.reload();
+    std::fs::remove_file(&path).unwrap();
+    assert!(outcome.contains("addresses requires a restart"), "{}", outcome);
+    assert!(outcome.contains("log files or the PID file requires a restart"), "{}", outcome);
+    assert_eq!(server.config().drain_grace, 7);
+    assert_eq!(server.config().log_file, None);
+    assert_eq!(server.config().address, Config::default().address);
+    assert!(failed.contains("Keeping the old configuration"), "{}", failed);
+    assert_eq!(server.config().drain_grace, 7);
+}
+
+#[test]
+fn router_patterns() {
+    let router = Router::<()>::new()
+        .route(&[Method::Get], "/a/:x", Access::Participant, |_, _| Ok(HttpOkay::Text("one".to_owned())))
+        .route(&[Method::Get], "/a/:x/*", Access::Participant, |_, _| Ok(HttpOkay::Text("rest".to_owned())))
+        .route(&[Method::Put], "/b", Access::Participant, |_, _| Ok(HttpOkay::Text("b".to_owned())));
+    let path = |p: &str| p.split('/').map(str::to_owned).collect::<Vec<_>>();
+    assert_eq!(router.find(&Method::Get, &path("a/1")).unwrap().1, ["1"]);
+    assert_eq!(router.find(&Method::Get, &path("a/1/2/3")).unwrap().1, ["1", "2/3"]);
+    assert!(matches!(router.find(&Method::Get, &path("a")), Err(HttpError::NotFound)));
+    assert!(matches!(router.find(&Method::Get, &path("b")), Err(HttpError::MethodNotAllowed(_))));
+    assert!(router.find(&Method::Head, &path("a/1")).is_ok());
+    assert!(matches!(router.find(&Method::Head, &path("b")), Err(HttpError::MethodNotAllowed(_))));
+}